@@ -1,6 +1,8 @@
 #!/bin/bash
 cd "$(dirname "$0")"
 (
+  # CustomResourceDefinition is generated from types in src/crd.rs
+  cargo run -q -- crd | ruby -ryaml -e 'puts YAML.load(ARGF.read).to_yaml'
   for x in setup_*.jsonnet; do
     jsonnet $x | ruby -ryaml -rjson -e 'puts JSON.parse(ARGF.read).to_yaml'
   done
@@ -0,0 +1,308 @@
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use kube::api::{ListParams, Meta, WatchEvent};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::crd::VaultStoreRule;
+use crate::leader_election::Leadership;
+use crate::retry::Backoff;
+use crate::runner::{self, Runner};
+use crate::vault_client;
+
+// Avoid tight loops when a deadline is already in the past after a run (e.g. renewBeforeSeconds longer than max_ttl)
+const MIN_REQUEUE_SECONDS: i64 = 30;
+const WATCH_TIMEOUT_SECONDS: u32 = 290;
+const WATCH_RETRY_SECONDS: u64 = 10;
+const LEADERSHIP_CHECK_SECONDS: u64 = 5;
+/// Rules are executed one by one; a run hung on Vault or Kubernetes API must not stall the others and the watch.
+pub const RULE_TIMEOUT_SECONDS: u64 = 300;
+
+pub struct Controller {
+    kube: kube::Client,
+    kube_crd: kube::Api<VaultStoreRule>,
+    vault_client: vault_client::Client,
+    namespace: Option<String>,
+    kube_backoff: Backoff,
+    resync_interval: chrono::Duration,
+    leadership: Option<Leadership>,
+    was_leader: bool,
+    rules: HashMap<String, RuleEntry>,
+}
+
+struct RuleEntry {
+    rule: VaultStoreRule,
+    reconciled: Option<Fingerprint>,
+    next_run_at: DateTime<Utc>,
+}
+
+// Changes to these fields trigger an immediate reconciliation. Status changes (including our own patches) don't.
+#[derive(PartialEq, Clone, Debug)]
+struct Fingerprint {
+    generation: Option<i64>,
+    annotations: Option<BTreeMap<String, String>>,
+    deleting: bool,
+}
+
+impl Fingerprint {
+    fn of(rule: &VaultStoreRule) -> Self {
+        Fingerprint {
+            generation: rule.metadata.generation,
+            annotations: rule.metadata.annotations.clone(),
+            deleting: rule.metadata.deletion_timestamp.is_some(),
+        }
+    }
+}
+
+impl Controller {
+    pub fn new(
+        kube_client: kube::Client,
+        vault_client: vault_client::Client,
+        namespace: Option<String>,
+        kube_backoff: Backoff,
+        resync_interval: chrono::Duration,
+        leadership: Option<Leadership>,
+    ) -> Self {
+        let kube_crd: kube::Api<VaultStoreRule> = if let Some(ns) = namespace.clone() {
+            kube::Api::namespaced(kube_client.clone(), &ns)
+        } else {
+            kube::Api::all(kube_client.clone())
+        };
+        Controller {
+            kube: kube_client,
+            kube_crd,
+            vault_client,
+            namespace,
+            kube_backoff,
+            resync_interval,
+            leadership,
+            was_leader: false,
+            rules: HashMap::new(),
+        }
+    }
+
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        log::info!("Starting controller...");
+        loop {
+            if let Err(e) = self.list_and_watch().await {
+                log::error!("!!!! Watch failed: {}", e);
+                tokio::time::delay_for(std::time::Duration::from_secs(WATCH_RETRY_SECONDS)).await;
+            }
+        }
+    }
+
+    // Returns Ok when the watch needs to be restarted from a fresh list
+    async fn list_and_watch(&mut self) -> anyhow::Result<()> {
+        let list = self.kube_crd.list(&ListParams::default()).await?;
+        let mut resource_version = list.metadata.resource_version.unwrap_or_default();
+
+        let mut seen = Vec::new();
+        for rule in list.items {
+            seen.push(rule_key(&rule));
+            self.observe(rule);
+        }
+        self.rules.retain(|key, _| seen.contains(key));
+
+        let api = self.kube_crd.clone();
+        loop {
+            let lp = ListParams::default().timeout(WATCH_TIMEOUT_SECONDS);
+            let mut stream = api.watch(&lp, &resource_version).await?.boxed();
+            loop {
+                self.reconcile_due().await;
+
+                tokio::select! {
+                    event = stream.next() => match event {
+                        Some(Ok(WatchEvent::Added(rule))) | Some(Ok(WatchEvent::Modified(rule))) => {
+                            if let Some(v) = Meta::resource_ver(&rule) {
+                                resource_version = v;
+                            }
+                            self.observe(rule);
+                        }
+                        Some(Ok(WatchEvent::Deleted(rule))) => {
+                            if let Some(v) = Meta::resource_ver(&rule) {
+                                resource_version = v;
+                            }
+                            log::info!("Rule deleted: {}", rule_key(&rule));
+                            self.rules.remove(&rule_key(&rule));
+                        }
+                        Some(Ok(WatchEvent::Bookmark(bm))) => {
+                            resource_version = bm.metadata.resource_version;
+                        }
+                        Some(Ok(WatchEvent::Error(e))) => {
+                            // 410 Gone: resourceVersion is too old, start over from a list
+                            if e.code == 410 {
+                                log::warn!("Watch desynced: {}", e);
+                                return Ok(());
+                            }
+                            return Err(e.into());
+                        }
+                        Some(Err(e)) => return Err(e.into()),
+                        // Watch timed out; continue from the last seen resourceVersion
+                        None => break,
+                    },
+                    _ = tokio::time::delay_for(self.duration_until_next_run()) => {}
+                }
+            }
+        }
+    }
+
+    fn observe(&mut self, rule: VaultStoreRule) {
+        let key = rule_key(&rule);
+        let fingerprint = Fingerprint::of(&rule);
+        if let Some(entry) = self.rules.get_mut(&key) {
+            if entry.reconciled.as_ref() != Some(&fingerprint) {
+                log::debug!("Rule changed: {}", &key);
+                entry.next_run_at = Utc::now();
+            }
+            entry.rule = rule;
+        } else {
+            log::debug!("Rule found: {}", &key);
+            self.rules.insert(
+                key,
+                RuleEntry {
+                    rule,
+                    reconciled: None,
+                    next_run_at: Utc::now(),
+                },
+            );
+        }
+    }
+
+    // Followers keep watching rules to take over quickly, but never execute them
+    fn check_leadership(&mut self) -> bool {
+        let is_leader = match &self.leadership {
+            Some(leadership) => {
+                leadership.progress();
+                leadership.is_leader()
+            }
+            None => true,
+        };
+        if is_leader && !self.was_leader {
+            // Other replica may have executed rules in the meantime; schedules we know are stale
+            let now = Utc::now();
+            for entry in self.rules.values_mut() {
+                entry.next_run_at = now;
+            }
+        }
+        self.was_leader = is_leader;
+        is_leader
+    }
+
+    async fn reconcile_due(&mut self) {
+        if !self.check_leadership() {
+            return;
+        }
+        let now = Utc::now();
+        let mut due: Vec<(DateTime<Utc>, String)> = self
+            .rules
+            .iter()
+            .filter(|(_, entry)| entry.next_run_at <= now)
+            .map(|(key, entry)| (entry.next_run_at, key.clone()))
+            .collect();
+        due.sort();
+        for (_, key) in due {
+            if !self.check_leadership() {
+                return;
+            }
+            self.reconcile(&key).await;
+        }
+    }
+
+    async fn reconcile(&mut self, key: &str) {
+        let rule = match self.rules.get(key) {
+            Some(entry) => entry.rule.clone(),
+            None => return,
+        };
+        log::info!("");
+        log::info!("Rule: {}", key);
+
+        // Runner takes a time on its creation to evaluate deadlines, so create one for each run
+        let runner = Runner::new(
+            self.kube.clone(),
+            self.vault_client.clone(),
+            self.namespace.clone(),
+            self.kube_backoff.clone(),
+        );
+        let run = tokio::time::timeout(
+            std::time::Duration::from_secs(RULE_TIMEOUT_SECONDS),
+            runner.run_rule(&rule),
+        );
+        // Another replica may take over once the leadership is lost; stop rather than racing with it
+        let leadership = self.leadership.clone();
+        let lost = async move {
+            match leadership {
+                Some(leadership) => leadership.lost().await,
+                None => futures::future::pending().await,
+            }
+        };
+        let rule_result = tokio::select! {
+            result = run => match result {
+                Ok(result) => result,
+                Err(_) => Err(format!("timed out after {} seconds", RULE_TIMEOUT_SECONDS).into()),
+            },
+            _ = lost => {
+                log::warn!("!!!! Lost the leadership; aborted the run");
+                return;
+            }
+        };
+
+        let now = Utc::now();
+        let next_run_at = match rule_result {
+            Ok(status) => runner::next_deadline(&rule, &status)
+                .map(|at| at.max(now + chrono::Duration::seconds(MIN_REQUEUE_SECONDS)))
+                .unwrap_or(now + self.resync_interval)
+                .min(now + self.resync_interval),
+            Err(e) => {
+                runner::log_rule_failure(e.as_ref());
+                // Back off exponentially on consecutive failures, counting this one
+                let failures = rule
+                    .status
+                    .as_ref()
+                    .and_then(|s| s.consecutive_failures)
+                    .unwrap_or(0);
+                let delay = runner::failure_backoff().delay(failures);
+                (now + chrono::Duration::from_std(delay).unwrap_or(self.resync_interval))
+                    .min(now + self.resync_interval)
+            }
+        };
+        log::info!("   * Next run at {:?}", next_run_at);
+
+        if let Some(entry) = self.rules.get_mut(key) {
+            entry.reconciled = Some(Fingerprint::of(&rule));
+            entry.next_run_at = next_run_at;
+        }
+    }
+
+    // Wake up periodically with leader election, to notice leadership changes and report progress to LeaderElector
+    fn duration_until_next_run(&self) -> std::time::Duration {
+        let check_interval = std::time::Duration::from_secs(LEADERSHIP_CHECK_SECONDS);
+        if self.leadership.is_some() && !self.was_leader {
+            return check_interval;
+        }
+        let now = Utc::now();
+        let until = self
+            .rules
+            .values()
+            .map(|entry| entry.next_run_at)
+            .min()
+            .unwrap_or(now + self.resync_interval)
+            .signed_duration_since(now)
+            .to_std()
+            .unwrap_or_else(|_| std::time::Duration::from_secs(0));
+        if self.leadership.is_some() {
+            until.min(check_interval)
+        } else {
+            until
+        }
+    }
+}
+
+fn rule_key(rule: &VaultStoreRule) -> String {
+    format!(
+        "{}/{}",
+        rule.metadata
+            .namespace
+            .as_ref()
+            .unwrap_or(&"default".to_string()),
+        Meta::name(rule),
+    )
+}
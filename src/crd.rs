@@ -1,20 +1,32 @@
 use chrono::{DateTime, Utc};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube_derive::CustomResource;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+
+pub mod v2;
 
 #[derive(CustomResource, Serialize, Deserialize, Clone, Debug, Default)]
 #[kube(
     group = "vault2kube.sorah.jp",
     version = "v1",
     namespaced,
-    kind = "VaultStoreRule"
+    kind = "VaultStoreRule",
+    shortname = "vaultrule"
 )]
 #[kube(status = "VaultStoreRuleStatus")]
 #[serde(rename_all = "camelCase")]
 pub struct VaultStoreRuleSpec {
     pub source_path: String,
+    pub source_kind: Option<String>,
     pub parameters: Option<serde_json::Map<String, serde_json::Value>>,
     pub destination_name: String,
+    pub destination_type: Option<String>,
+    pub destination_labels: Option<BTreeMap<String, String>>,
+    pub destination_annotations: Option<BTreeMap<String, String>>,
+    pub secret_deletion_policy: Option<String>,
+    pub owner_reference: Option<bool>,
     pub templates: Vec<VaultStoreRuleTemplate>,
     pub rollout_restarts: Option<Vec<VaultStoreRuleRollout>>,
     pub renew_before_seconds: Option<i32>,
@@ -40,9 +52,522 @@ pub struct VaultStoreRuleStatus {
     pub lease_id: Option<String>,
     pub ttl: Option<u32>,
     pub expires_at: Option<DateTime<Utc>>,
+    pub renewable: Option<bool>,
+    pub lease_lost_at: Option<DateTime<Utc>>,
+    pub source_version: Option<u64>,
+    pub source_rotated_at: Option<DateTime<Utc>>,
     pub next_lease_id: Option<String>,
     pub last_lease_id: Option<String>,
     pub rotated_at: Option<DateTime<Utc>>,
+    pub rollout_pending_since: Option<DateTime<Utc>>,
     pub last_run_started_at: Option<DateTime<Utc>>,
     pub last_successful_run_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub last_failed_at: Option<DateTime<Utc>>,
+    pub consecutive_failures: Option<u32>,
+    pub observed_generation: Option<i64>,
+    pub conditions: Option<Vec<VaultStoreRuleCondition>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultStoreRuleCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub last_transition_time: Option<DateTime<Utc>>,
+}
+
+/// Service serving the conversion webhook (`vault2kube webhook`) at /convert.
+pub struct ConversionWebhook {
+    pub namespace: String,
+    pub name: String,
+    pub port: i32,
+    /// PEM encoded CA certificates to verify the webhook
+    pub ca_bundle: Option<String>,
+}
+
+/// Builds CustomResourceDefinition of VaultStoreRule with a structural schema and printer columns.
+///
+/// kube-derive doesn't generate a validation schema, so schemas are defined next to each type. Tests check that they
+/// cover every field. v2 is served only with a conversion webhook, as its schema differs from v1.
+pub fn custom_resource_definition(
+    conversion_webhook: Option<&ConversionWebhook>,
+) -> CustomResourceDefinition {
+    let crd = VaultStoreRule::crd();
+    let mut value = serde_json::to_value(&crd).expect("valid custom resource definition");
+    // kube-derive puts additionalPrinterColumns in the CRD spec, which is only valid for apiextensions.k8s.io/v1beta1
+    if let Some(spec) = value["spec"].as_object_mut() {
+        spec.remove("additionalPrinterColumns");
+    }
+    let mut versions = vec![json!({
+        "name": "v1",
+        "served": true,
+        "storage": true,
+        "subresources": { "status": {} },
+        "schema": {
+            "openAPIV3Schema": {
+                "type": "object",
+                "properties": {
+                    "spec": VaultStoreRuleSpec::schema(),
+                    "status": VaultStoreRuleStatus::schema(),
+                },
+                "required": ["spec"],
+            },
+        },
+        "additionalPrinterColumns": printer_columns(".spec.destinationName"),
+    })];
+    if let Some(webhook) = conversion_webhook {
+        versions.push(json!({
+            "name": "v2",
+            "served": true,
+            "storage": false,
+            "subresources": { "status": {} },
+            "schema": {
+                "openAPIV3Schema": {
+                    "type": "object",
+                    "properties": {
+                        "spec": v2::VaultStoreRuleSpec::schema(),
+                        "status": VaultStoreRuleStatus::schema(),
+                    },
+                    "required": ["spec"],
+                },
+            },
+            "additionalPrinterColumns": printer_columns(".spec.destination.name"),
+        }));
+        let mut client_config = json!({
+            "service": {
+                "namespace": &webhook.namespace,
+                "name": &webhook.name,
+                "port": webhook.port,
+                "path": "/convert",
+            },
+        });
+        if let Some(ca_bundle) = &webhook.ca_bundle {
+            client_config["caBundle"] = json!(base64::encode(ca_bundle));
+        }
+        value["spec"]["conversion"] = json!({
+            "strategy": "Webhook",
+            "webhook": {
+                "conversionReviewVersions": ["v1"],
+                "clientConfig": client_config,
+            },
+        });
+    }
+    value["spec"]["versions"] = json!(versions);
+    serde_json::from_value(value).expect("valid custom resource definition")
+}
+
+fn printer_columns(destination_path: &str) -> serde_json::Value {
+    json!([
+        { "name": "Destination", "type": "string", "jsonPath": destination_path },
+        { "name": "Expires At", "type": "date", "jsonPath": ".status.expiresAt" },
+        { "name": "Last Success", "type": "date", "jsonPath": ".status.lastSuccessfulRunAt" },
+        { "name": "Age", "type": "date", "jsonPath": ".metadata.creationTimestamp" },
+    ])
+}
+
+/// Converts a VaultStoreRule object to the given apiVersion. Fields other than spec are kept as is.
+pub fn convert(
+    mut object: serde_json::Value,
+    desired_api_version: &str,
+) -> anyhow::Result<serde_json::Value> {
+    let api_version = object["apiVersion"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    if api_version == desired_api_version {
+        return Ok(object);
+    }
+    let spec = object["spec"].take();
+    let v1: VaultStoreRuleSpec = match api_version.as_str() {
+        "vault2kube.sorah.jp/v1" => serde_json::from_value(spec)?,
+        "vault2kube.sorah.jp/v2" => serde_json::from_value::<v2::VaultStoreRuleSpec>(spec)?.into(),
+        _ => return Err(anyhow::anyhow!("unsupported apiVersion: {}", api_version)),
+    };
+    object["spec"] = match desired_api_version {
+        "vault2kube.sorah.jp/v1" => serde_json::to_value(v1)?,
+        "vault2kube.sorah.jp/v2" => serde_json::to_value(v2::VaultStoreRuleSpec::from(v1))?,
+        _ => {
+            return Err(anyhow::anyhow!(
+                "unsupported apiVersion: {}",
+                desired_api_version
+            ))
+        }
+    };
+    // Omit absent fields rather than nulls, so the API server applies defaults in the schema
+    strip_nulls(&mut object["spec"]);
+    object["apiVersion"] = json!(desired_api_version);
+    Ok(object)
+}
+
+fn strip_nulls(value: &mut serde_json::Value) {
+    if let Some(map) = value.as_object_mut() {
+        let nulls: Vec<String> = map
+            .iter()
+            .filter(|(_, v)| v.is_null())
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in nulls {
+            map.remove(&key);
+        }
+        for (key, v) in map.iter_mut() {
+            // parameters are passed to Vault as is
+            if key != "parameters" {
+                strip_nulls(v);
+            }
+        }
+    }
+}
+
+impl VaultStoreRuleSpec {
+    fn schema() -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "sourcePath": { "type": "string" },
+                "sourceKind": {
+                    "type": "string",
+                    "enum": ["lease", "kv-v2", "database-static"],
+                    "default": "lease",
+                    "nullable": true,
+                },
+                "parameters": {
+                    "type": "object",
+                    "x-kubernetes-preserve-unknown-fields": true,
+                    "nullable": true,
+                },
+                "destinationName": { "type": "string" },
+                "destinationType": {
+                    "type": "string",
+                    "enum": [
+                        "Opaque",
+                        "kubernetes.io/tls",
+                        "kubernetes.io/dockerconfigjson",
+                        "kubernetes.io/dockercfg",
+                        "kubernetes.io/basic-auth",
+                        "kubernetes.io/ssh-auth",
+                    ],
+                    "default": "Opaque",
+                    "nullable": true,
+                },
+                "destinationLabels": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string" },
+                    "nullable": true,
+                },
+                "destinationAnnotations": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string" },
+                    "nullable": true,
+                },
+                "secretDeletionPolicy": {
+                    "type": "string",
+                    "enum": ["Retain", "Delete"],
+                    "default": "Retain",
+                    "nullable": true,
+                },
+                "ownerReference": { "type": "boolean", "default": true, "nullable": true },
+                "templates": { "type": "array", "items": VaultStoreRuleTemplate::schema() },
+                "rolloutRestarts": {
+                    "type": "array",
+                    "items": VaultStoreRuleRollout::schema(),
+                    "nullable": true,
+                },
+                "renewBeforeSeconds": { "type": "integer", "minimum": 0, "nullable": true },
+                "rotateBeforeSeconds": { "type": "integer", "minimum": 0, "nullable": true },
+                "revokeAfterSeconds": { "type": "integer", "minimum": 0, "default": 1, "nullable": true },
+            },
+            "required": ["sourcePath", "destinationName", "templates"],
+        })
+    }
+}
+
+impl VaultStoreRuleTemplate {
+    fn schema() -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "key": { "type": "string" },
+                "template": { "type": "string" },
+            },
+            "required": ["key", "template"],
+        })
+    }
+}
+
+impl VaultStoreRuleRollout {
+    fn schema() -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "kind": { "type": "string", "enum": ["Deployment", "DaemonSet", "StatefulSet"] },
+                "name": { "type": "string" },
+            },
+            "required": ["kind", "name"],
+        })
+    }
+}
+
+impl VaultStoreRuleStatus {
+    fn schema() -> serde_json::Value {
+        let string = json!({ "type": "string", "nullable": true });
+        let time = json!({ "type": "string", "format": "date-time", "nullable": true });
+        let count = json!({ "type": "integer", "minimum": 0, "nullable": true });
+        json!({
+            "type": "object",
+            "properties": {
+                "leaseId": string,
+                "ttl": count,
+                "expiresAt": time,
+                "renewable": { "type": "boolean", "nullable": true },
+                "leaseLostAt": time,
+                "sourceVersion": count,
+                "sourceRotatedAt": time,
+                "nextLeaseId": string,
+                "lastLeaseId": string,
+                "rotatedAt": time,
+                "rolloutPendingSince": time,
+                "lastRunStartedAt": time,
+                "lastSuccessfulRunAt": time,
+                "lastError": string,
+                "lastFailedAt": time,
+                "consecutiveFailures": count,
+                "observedGeneration": { "type": "integer", "nullable": true },
+                "conditions": {
+                    "type": "array",
+                    "items": VaultStoreRuleCondition::schema(),
+                    "nullable": true,
+                },
+            },
+        })
+    }
+}
+
+impl VaultStoreRuleCondition {
+    fn schema() -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "type": { "type": "string" },
+                "status": { "type": "string", "enum": ["True", "False", "Unknown"] },
+                "reason": { "type": "string", "nullable": true },
+                "message": { "type": "string", "nullable": true },
+                "lastTransitionTime": { "type": "string", "format": "date-time", "nullable": true },
+            },
+            "required": ["type", "status"],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn full_spec() -> VaultStoreRuleSpec {
+        let mut parameters = serde_json::Map::new();
+        parameters.insert("ttl".to_string(), json!("1h"));
+        parameters.insert("nested".to_string(), json!({ "a": null, "b": [1, null] }));
+        parameters.insert("empty".to_string(), Value::Null);
+        let mut labels = BTreeMap::new();
+        labels.insert("app".to_string(), "{{rule.name}}".to_string());
+        let mut annotations = BTreeMap::new();
+        annotations.insert(
+            "example.com/lease".to_string(),
+            "{{lease.lease_id}}".to_string(),
+        );
+        VaultStoreRuleSpec {
+            source_path: "database/creds/app".to_string(),
+            source_kind: Some("lease".to_string()),
+            parameters: Some(parameters),
+            destination_name: "app-db".to_string(),
+            destination_type: Some("kubernetes.io/basic-auth".to_string()),
+            destination_labels: Some(labels),
+            destination_annotations: Some(annotations),
+            secret_deletion_policy: Some("Delete".to_string()),
+            owner_reference: Some(false),
+            templates: vec![
+                VaultStoreRuleTemplate {
+                    key: "username".to_string(),
+                    template: "{{username}}".to_string(),
+                },
+                VaultStoreRuleTemplate {
+                    key: "password".to_string(),
+                    template: "{{password}}".to_string(),
+                },
+            ],
+            rollout_restarts: Some(vec![VaultStoreRuleRollout {
+                kind: "Deployment".to_string(),
+                name: "app".to_string(),
+            }]),
+            renew_before_seconds: Some(600),
+            rotate_before_seconds: Some(300),
+            revoke_after_seconds: Some(60),
+        }
+    }
+
+    fn full_status() -> VaultStoreRuleStatus {
+        let now = Utc::now();
+        VaultStoreRuleStatus {
+            lease_id: Some("database/creds/app/abc".to_string()),
+            ttl: Some(3600),
+            expires_at: Some(now),
+            renewable: Some(true),
+            lease_lost_at: Some(now),
+            source_version: Some(3),
+            source_rotated_at: Some(now),
+            next_lease_id: Some("database/creds/app/def".to_string()),
+            last_lease_id: Some("database/creds/app/ghi".to_string()),
+            rotated_at: Some(now),
+            rollout_pending_since: Some(now),
+            last_run_started_at: Some(now),
+            last_successful_run_at: Some(now),
+            last_error: Some("error".to_string()),
+            last_failed_at: Some(now),
+            consecutive_failures: Some(1),
+            observed_generation: Some(2),
+            conditions: Some(vec![VaultStoreRuleCondition {
+                type_: "Ready".to_string(),
+                status: "True".to_string(),
+                reason: Some("Succeeded".to_string()),
+                message: Some("message".to_string()),
+                last_transition_time: Some(now),
+            }]),
+        }
+    }
+
+    // Asserts every field in a serialized value is declared in a schema with a matching type
+    fn assert_covered(value: &Value, schema: &Value, path: &str) {
+        let type_ = match value {
+            Value::Null => return,
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "integer",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        };
+        assert_eq!(schema["type"], json!(type_), "type of {}", path);
+        match value {
+            Value::Array(items) => {
+                for item in items {
+                    assert_covered(item, &schema["items"], &format!("{}[]", path));
+                }
+            }
+            Value::Object(_) if schema["x-kubernetes-preserve-unknown-fields"] == json!(true) => {}
+            Value::Object(map) => {
+                for (key, v) in map {
+                    let property = match schema.get("additionalProperties") {
+                        Some(additional) => additional,
+                        None => &schema["properties"][key],
+                    };
+                    let path = format!("{}.{}", path, key);
+                    assert!(!property.is_null(), "{} is missing in schema", path);
+                    assert_covered(v, property, &path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_schema_covers_spec() {
+        let spec = serde_json::to_value(full_spec()).unwrap();
+        assert_covered(&spec, &VaultStoreRuleSpec::schema(), "spec");
+    }
+
+    #[test]
+    fn test_schema_covers_v2_spec() {
+        let spec = serde_json::to_value(v2::VaultStoreRuleSpec::from(full_spec())).unwrap();
+        assert_covered(&spec, &v2::VaultStoreRuleSpec::schema(), "spec");
+    }
+
+    #[test]
+    fn test_schema_covers_status() {
+        let status = serde_json::to_value(full_status()).unwrap();
+        assert_covered(&status, &VaultStoreRuleStatus::schema(), "status");
+    }
+
+    fn object(api_version: &str, spec: Value) -> Value {
+        json!({
+            "apiVersion": api_version,
+            "kind": "VaultStoreRule",
+            "metadata": { "name": "app", "namespace": "default", "generation": 2 },
+            "spec": spec,
+            "status": full_status(),
+        })
+    }
+
+    #[test]
+    fn test_convert_v1_round_trip() {
+        let v1 = object(
+            "vault2kube.sorah.jp/v1",
+            serde_json::to_value(full_spec()).unwrap(),
+        );
+        let v2 = convert(v1.clone(), "vault2kube.sorah.jp/v2").unwrap();
+        assert_eq!(v2["apiVersion"], json!("vault2kube.sorah.jp/v2"));
+        assert_eq!(v2["spec"]["source"]["path"], json!("database/creds/app"));
+        assert_eq!(v2["spec"]["lifecycle"]["renewBeforeSeconds"], json!(600));
+        assert_eq!(v2["status"], v1["status"]);
+        assert_eq!(convert(v2, "vault2kube.sorah.jp/v1").unwrap(), v1);
+    }
+
+    #[test]
+    fn test_convert_v2_round_trip() {
+        let v2 = object(
+            "vault2kube.sorah.jp/v2",
+            serde_json::to_value(v2::VaultStoreRuleSpec::from(full_spec())).unwrap(),
+        );
+        let v1 = convert(v2.clone(), "vault2kube.sorah.jp/v1").unwrap();
+        assert_eq!(v1["spec"]["sourcePath"], json!("database/creds/app"));
+        assert_eq!(v1["spec"]["parameters"]["empty"], Value::Null);
+        assert_eq!(convert(v1, "vault2kube.sorah.jp/v2").unwrap(), v2);
+    }
+
+    #[test]
+    fn test_convert_v2_empty_lifecycle() {
+        let v2 = object(
+            "vault2kube.sorah.jp/v2",
+            json!({
+                "source": { "path": "secret/data/app", "kind": "kv-v2" },
+                "destination": {
+                    "name": "app",
+                    "templates": [{ "key": "password", "template": "{{password}}" }],
+                },
+                "lifecycle": {},
+            }),
+        );
+        let v1 = convert(v2.clone(), "vault2kube.sorah.jp/v1").unwrap();
+        assert_eq!(convert(v1, "vault2kube.sorah.jp/v2").unwrap(), v2);
+    }
+
+    #[test]
+    fn test_convert_unsupported_version() {
+        let v1 = object(
+            "vault2kube.sorah.jp/v1",
+            serde_json::to_value(full_spec()).unwrap(),
+        );
+        assert!(convert(v1, "vault2kube.sorah.jp/v3").is_err());
+    }
+
+    #[test]
+    fn test_custom_resource_definition() {
+        let crd = serde_json::to_value(custom_resource_definition(None)).unwrap();
+        assert_eq!(crd["spec"]["versions"].as_array().unwrap().len(), 1);
+        assert!(crd["spec"]["conversion"].is_null());
+
+        let webhook = ConversionWebhook {
+            namespace: "vault2kube".to_string(),
+            name: "vault2kube-webhook".to_string(),
+            port: 443,
+            ca_bundle: None,
+        };
+        let crd = serde_json::to_value(custom_resource_definition(Some(&webhook))).unwrap();
+        let versions: Vec<&Value> = crd["spec"]["versions"].as_array().unwrap().iter().collect();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0]["storage"], json!(true));
+        assert_eq!(crd["spec"]["conversion"]["strategy"], json!("Webhook"));
+    }
 }
@@ -0,0 +1,154 @@
+//! vault2kube.sorah.jp/v2 VaultStoreRule, which groups v1 fields into nested sections.
+//!
+//! v1 remains the storage version and the version vault2kube works with; the conversion webhook converts objects
+//! between versions using From implementations below. Every field must have a counterpart in v1 to keep conversions
+//! lossless.
+
+use kube_derive::CustomResource;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+
+use super::{VaultStoreRuleRollout, VaultStoreRuleStatus, VaultStoreRuleTemplate};
+
+#[derive(CustomResource, Serialize, Deserialize, Clone, Debug, Default)]
+#[kube(
+    group = "vault2kube.sorah.jp",
+    version = "v2",
+    namespaced,
+    kind = "VaultStoreRule",
+    shortname = "vaultrule"
+)]
+#[kube(status = "VaultStoreRuleStatus")]
+#[serde(rename_all = "camelCase")]
+pub struct VaultStoreRuleSpec {
+    pub source: VaultStoreRuleSource,
+    pub destination: VaultStoreRuleDestination,
+    // Not optional, so an empty lifecycle survives conversions through v1 which has no counterpart of it
+    #[serde(default)]
+    pub lifecycle: VaultStoreRuleLifecycle,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultStoreRuleSource {
+    pub path: String,
+    pub kind: Option<String>,
+    pub parameters: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultStoreRuleDestination {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub labels: Option<BTreeMap<String, String>>,
+    pub annotations: Option<BTreeMap<String, String>>,
+    pub templates: Vec<VaultStoreRuleTemplate>,
+    pub deletion_policy: Option<String>,
+    pub owner_reference: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultStoreRuleLifecycle {
+    pub renew_before_seconds: Option<i32>,
+    pub rotate_before_seconds: Option<i32>,
+    pub revoke_after_seconds: Option<i32>,
+    pub rollout_restarts: Option<Vec<VaultStoreRuleRollout>>,
+}
+
+impl From<super::VaultStoreRuleSpec> for VaultStoreRuleSpec {
+    fn from(v1: super::VaultStoreRuleSpec) -> Self {
+        VaultStoreRuleSpec {
+            source: VaultStoreRuleSource {
+                path: v1.source_path,
+                kind: v1.source_kind,
+                parameters: v1.parameters,
+            },
+            destination: VaultStoreRuleDestination {
+                name: v1.destination_name,
+                type_: v1.destination_type,
+                labels: v1.destination_labels,
+                annotations: v1.destination_annotations,
+                templates: v1.templates,
+                deletion_policy: v1.secret_deletion_policy,
+                owner_reference: v1.owner_reference,
+            },
+            lifecycle: VaultStoreRuleLifecycle {
+                renew_before_seconds: v1.renew_before_seconds,
+                rotate_before_seconds: v1.rotate_before_seconds,
+                revoke_after_seconds: v1.revoke_after_seconds,
+                rollout_restarts: v1.rollout_restarts,
+            },
+        }
+    }
+}
+
+impl From<VaultStoreRuleSpec> for super::VaultStoreRuleSpec {
+    fn from(v2: VaultStoreRuleSpec) -> Self {
+        let lifecycle = v2.lifecycle;
+        super::VaultStoreRuleSpec {
+            source_path: v2.source.path,
+            source_kind: v2.source.kind,
+            parameters: v2.source.parameters,
+            destination_name: v2.destination.name,
+            destination_type: v2.destination.type_,
+            destination_labels: v2.destination.labels,
+            destination_annotations: v2.destination.annotations,
+            secret_deletion_policy: v2.destination.deletion_policy,
+            owner_reference: v2.destination.owner_reference,
+            templates: v2.destination.templates,
+            rollout_restarts: lifecycle.rollout_restarts,
+            renew_before_seconds: lifecycle.renew_before_seconds,
+            rotate_before_seconds: lifecycle.rotate_before_seconds,
+            revoke_after_seconds: lifecycle.revoke_after_seconds,
+        }
+    }
+}
+
+impl VaultStoreRuleSpec {
+    pub(super) fn schema() -> serde_json::Value {
+        let v1 = super::VaultStoreRuleSpec::schema();
+        let field = |name: &str| v1["properties"][name].clone();
+        json!({
+            "type": "object",
+            "properties": {
+                "source": {
+                    "type": "object",
+                    "properties": {
+                        "path": field("sourcePath"),
+                        "kind": field("sourceKind"),
+                        "parameters": field("parameters"),
+                    },
+                    "required": ["path"],
+                },
+                "destination": {
+                    "type": "object",
+                    "properties": {
+                        "name": field("destinationName"),
+                        "type": field("destinationType"),
+                        "labels": field("destinationLabels"),
+                        "annotations": field("destinationAnnotations"),
+                        "templates": field("templates"),
+                        "deletionPolicy": field("secretDeletionPolicy"),
+                        "ownerReference": field("ownerReference"),
+                    },
+                    "required": ["name", "templates"],
+                },
+                "lifecycle": {
+                    "type": "object",
+                    "properties": {
+                        "renewBeforeSeconds": field("renewBeforeSeconds"),
+                        "rotateBeforeSeconds": field("rotateBeforeSeconds"),
+                        "revokeAfterSeconds": field("revokeAfterSeconds"),
+                        "rolloutRestarts": field("rolloutRestarts"),
+                    },
+                    "default": {},
+                },
+            },
+            "required": ["source", "destination"],
+        })
+    }
+}
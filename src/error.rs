@@ -6,4 +6,192 @@ pub enum Error {
     RuleExecutionFailed,
     #[error("Unsupported kind for rollout")]
     UnsupportedRolloutKind,
+    #[error("Unsupported kind for source")]
+    UnsupportedSourceKind,
+    #[error("Unsupported destination type: {0}")]
+    UnsupportedDestinationType(String),
+    #[error("Secret type {0} requires a key: {1}")]
+    MissingSecretKey(String, String),
+}
+
+/// Errors from Vault API, classified by status codes and messages in `errors` array of a response.
+#[derive(Error, Debug)]
+pub enum VaultError {
+    #[error("Vault permission denied: {}", .0.join(", "))]
+    PermissionDenied(Vec<String>),
+    #[error("Vault lease not found: {}", .0.join(", "))]
+    LeaseNotFound(Vec<String>),
+    #[error("Vault rate limited: {}", .0.join(", "))]
+    RateLimited(Vec<String>),
+    #[error("Vault is sealed: {}", .0.join(", "))]
+    Sealed(Vec<String>),
+    #[error("Vault is in standby: {}", .0.join(", "))]
+    Standby(Vec<String>),
+    #[error("Vault returned HTTP {status}: {}", .errors.join(", "))]
+    Api { status: u16, errors: Vec<String> },
+    #[error("Vault network error: {0}")]
+    Network(reqwest::Error),
+    #[error("Vault returned an invalid response: {0}")]
+    InvalidResponse(reqwest::Error),
+}
+
+impl VaultError {
+    pub fn from_response(status: reqwest::StatusCode, errors: Vec<String>) -> Self {
+        let contains = |needle: &str| errors.iter().any(|e| e.to_lowercase().contains(needle));
+        match status.as_u16() {
+            400 | 404 if contains("lease not found") || contains("invalid lease") => {
+                VaultError::LeaseNotFound(errors)
+            }
+            403 => VaultError::PermissionDenied(errors),
+            429 => VaultError::RateLimited(errors),
+            503 if contains("sealed") => VaultError::Sealed(errors),
+            503 if contains("standby") || contains("not active") => VaultError::Standby(errors),
+            status => VaultError::Api { status, errors },
+        }
+    }
+
+    /// Returns true for errors which may succeed when retried later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            VaultError::RateLimited(_)
+            | VaultError::Sealed(_)
+            | VaultError::Standby(_)
+            | VaultError::Network(_) => true,
+            VaultError::Api { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// Returns true when retrying the request may help. Non-idempotent requests (e.g. ones minting new leases) are
+    /// retried only when they surely didn't reach Vault backends, so retries never duplicate their effects.
+    pub fn is_retryable(&self, idempotent: bool) -> bool {
+        match self {
+            VaultError::RateLimited(_) | VaultError::Sealed(_) | VaultError::Standby(_) => true,
+            VaultError::Network(e) => idempotent || is_connect_error(e),
+            VaultError::Api { status, .. } => idempotent && *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for VaultError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            VaultError::InvalidResponse(e)
+        } else {
+            VaultError::Network(e)
+        }
+    }
+}
+
+// Failures on establishing a connection mean a request never reached Vault
+fn is_connect_error(e: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(e);
+    while let Some(err) = source {
+        if let Some(hyper_error) = err.downcast_ref::<hyper::Error>() {
+            return hyper_error.is_connect();
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    fn from_response(status: u16, error: &str) -> VaultError {
+        VaultError::from_response(
+            StatusCode::from_u16(status).unwrap(),
+            vec![error.to_string()],
+        )
+    }
+
+    #[test]
+    fn test_from_response() {
+        assert!(matches!(
+            from_response(400, "lease not found"),
+            VaultError::LeaseNotFound(_)
+        ));
+        assert!(matches!(
+            from_response(400, "invalid lease"),
+            VaultError::LeaseNotFound(_)
+        ));
+        assert!(matches!(
+            from_response(404, "Lease Not Found"),
+            VaultError::LeaseNotFound(_)
+        ));
+        assert!(matches!(
+            from_response(400, "missing client token"),
+            VaultError::Api { status: 400, .. }
+        ));
+        assert!(matches!(
+            from_response(404, ""),
+            VaultError::Api { status: 404, .. }
+        ));
+        assert!(matches!(
+            from_response(403, "permission denied"),
+            VaultError::PermissionDenied(_)
+        ));
+        assert!(matches!(
+            from_response(429, "rate limit quota exceeded"),
+            VaultError::RateLimited(_)
+        ));
+        assert!(matches!(
+            from_response(503, "Vault is sealed"),
+            VaultError::Sealed(_)
+        ));
+        assert!(matches!(
+            from_response(503, "node is in standby mode"),
+            VaultError::Standby(_)
+        ));
+        assert!(matches!(
+            from_response(503, "node not active but active cluster node not found"),
+            VaultError::Standby(_)
+        ));
+        assert!(matches!(
+            from_response(503, "unavailable"),
+            VaultError::Api { status: 503, .. }
+        ));
+        assert!(matches!(
+            from_response(500, "internal error"),
+            VaultError::Api { status: 500, .. }
+        ));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        // Rejected before reaching backends; safe to retry even for requests minting leases
+        for e in &[
+            from_response(429, "rate limit quota exceeded"),
+            from_response(503, "Vault is sealed"),
+            from_response(503, "node is in standby mode"),
+        ] {
+            assert!(e.is_retryable(false), "{}", e);
+            assert!(e.is_retryable(true), "{}", e);
+            assert!(e.is_transient(), "{}", e);
+        }
+
+        // May have reached backends
+        for e in &[
+            from_response(500, "internal error"),
+            from_response(503, "unavailable"),
+        ] {
+            assert!(!e.is_retryable(false), "{}", e);
+            assert!(e.is_retryable(true), "{}", e);
+            assert!(e.is_transient(), "{}", e);
+        }
+
+        // Never succeed on retries
+        for e in &[
+            from_response(400, "lease not found"),
+            from_response(400, "missing client token"),
+            from_response(403, "permission denied"),
+        ] {
+            assert!(!e.is_retryable(false), "{}", e);
+            assert!(!e.is_retryable(true), "{}", e);
+            assert!(!e.is_transient(), "{}", e);
+        }
+    }
 }
@@ -0,0 +1,220 @@
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use std::sync::{Arc, Mutex};
+
+/// Elects a single leader among replicas using a coordination.k8s.io/v1 Lease object.
+pub struct LeaderElector {
+    leases: kube::Api<Lease>,
+    name: String,
+    identity: String,
+    lease_duration: chrono::Duration,
+    progress_timeout: chrono::Duration,
+    leadership: Leadership,
+    // resourceVersion of the lease last seen, and the local time when it was first seen. Expiry is judged by the local
+    // clock from that time like client-go, as renewTime written by another replica is subject to clock skew.
+    observed: Option<(String, DateTime<Utc>)>,
+}
+
+/// Shared view of the current leadership, updated by LeaderElector.
+#[derive(Clone)]
+pub struct Leadership {
+    leading_until: Arc<Mutex<Option<DateTime<Utc>>>>,
+    progressed_at: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl Leadership {
+    fn new() -> Self {
+        Leadership {
+            leading_until: Arc::new(Mutex::new(None)),
+            progressed_at: Arc::new(Mutex::new(Utc::now())),
+        }
+    }
+
+    /// Returns true while the lease held by this replica is known to be valid.
+    pub fn is_leader(&self) -> bool {
+        self.leading_until
+            .lock()
+            .unwrap()
+            .map(|until| Utc::now() < until)
+            .unwrap_or(false)
+    }
+
+    /// Resolves when this replica is no longer the leader, to abort work in flight.
+    pub async fn lost(&self) {
+        while self.is_leader() {
+            tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+        }
+    }
+
+    /// Tells that the controller is making progress. LeaderElector stops renewing the lease when this is not called
+    /// for a while, so a stuck leader hands over the leadership.
+    pub fn progress(&self) {
+        *self.progressed_at.lock().unwrap() = Utc::now();
+    }
+
+    fn progressed_within(&self, timeout: chrono::Duration) -> bool {
+        Utc::now() < *self.progressed_at.lock().unwrap() + timeout
+    }
+
+    fn set(&self, leading_until: Option<DateTime<Utc>>) {
+        *self.leading_until.lock().unwrap() = leading_until;
+    }
+}
+
+impl LeaderElector {
+    pub fn new(
+        kube_client: kube::Client,
+        namespace: &str,
+        name: String,
+        identity: String,
+        lease_duration: chrono::Duration,
+        progress_timeout: chrono::Duration,
+    ) -> Self {
+        LeaderElector {
+            leases: kube::Api::namespaced(kube_client, namespace),
+            name,
+            identity,
+            lease_duration,
+            progress_timeout,
+            leadership: Leadership::new(),
+            observed: None,
+        }
+    }
+
+    pub fn leadership(&self) -> Leadership {
+        self.leadership.clone()
+    }
+
+    /// Keeps trying to acquire or renew the lease. Never returns.
+    pub async fn run(mut self) {
+        let retry_period = (self.lease_duration / 3)
+            .to_std()
+            .unwrap_or_else(|_| std::time::Duration::from_secs(1));
+        log::info!(
+            "Leader election: lease={}, identity={}",
+            &self.name,
+            &self.identity
+        );
+        loop {
+            let was_leader = self.leadership.is_leader();
+            if !self.leadership.progressed_within(self.progress_timeout) {
+                // Leadership expires by itself, then followers take over
+                log::error!(
+                    "!!!! Leader election: controller seems to be stuck; not renewing the lease"
+                );
+                tokio::time::delay_for(retry_period).await;
+                continue;
+            }
+            match self.try_acquire_or_renew().await {
+                Ok(Some(leading_until)) => {
+                    if !was_leader {
+                        log::info!("Leader election: became the leader");
+                    }
+                    self.leadership.set(Some(leading_until));
+                }
+                Ok(None) => {
+                    if was_leader {
+                        log::warn!("Leader election: lost the leadership");
+                    }
+                    self.leadership.set(None);
+                }
+                // Keep the current state; leadership expires by itself unless renewed in time
+                Err(e) => log::error!("!!!! Leader election failed: {}", e),
+            }
+            tokio::time::delay_for(retry_period).await;
+        }
+    }
+
+    // Returns the time until this replica leads, or None when another replica holds the lease
+    async fn try_acquire_or_renew(&mut self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let now = Utc::now();
+        let lease = match self.leases.get(&self.name).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(ae)) if ae.code == 404 => {
+                return self.create(now).await;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = lease.spec.clone().unwrap_or_default();
+        let holder = spec.holder_identity.clone();
+        let is_holder = holder.as_ref() == Some(&self.identity);
+        let observed_at = match (&self.observed, &lease.metadata.resource_version) {
+            (Some((observed_version, observed_at)), Some(version))
+                if observed_version == version =>
+            {
+                *observed_at
+            }
+            _ => now,
+        };
+        self.observed = lease
+            .metadata
+            .resource_version
+            .clone()
+            .map(|version| (version, observed_at));
+        let expired = match (&holder, spec.lease_duration_seconds) {
+            (Some(_), Some(duration)) => {
+                observed_at + chrono::Duration::seconds(duration as i64) <= now
+            }
+            _ => true,
+        };
+        if !is_holder && !expired {
+            log::debug!("Leader election: lease is held by {:?}", holder);
+            return Ok(None);
+        }
+
+        let mut new_spec = spec.clone();
+        new_spec.holder_identity = Some(self.identity.clone());
+        new_spec.lease_duration_seconds = Some(self.lease_duration.num_seconds() as i32);
+        new_spec.renew_time = Some(MicroTime(now));
+        if !is_holder {
+            log::info!(
+                "Leader election: taking over the expired lease from {:?}",
+                holder
+            );
+            new_spec.acquire_time = Some(MicroTime(now));
+            new_spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+        }
+        let new_lease = Lease {
+            metadata: lease.metadata.clone(),
+            spec: Some(new_spec),
+        };
+
+        // Lease metadata carries resourceVersion, so concurrent updates by other replicas fail with 409
+        match self
+            .leases
+            .replace(&self.name, &kube::api::PostParams::default(), &new_lease)
+            .await
+        {
+            Ok(_) => Ok(Some(now + self.lease_duration)),
+            Err(kube::Error::Api(ae)) if ae.code == 409 => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn create(&self, now: DateTime<Utc>) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let lease = Lease {
+            metadata: k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta {
+                name: Some(self.name.clone()),
+                ..Default::default()
+            },
+            spec: Some(LeaseSpec {
+                holder_identity: Some(self.identity.clone()),
+                lease_duration_seconds: Some(self.lease_duration.num_seconds() as i32),
+                acquire_time: Some(MicroTime(now)),
+                renew_time: Some(MicroTime(now)),
+                lease_transitions: Some(0),
+            }),
+        };
+        match self
+            .leases
+            .create(&kube::api::PostParams::default(), &lease)
+            .await
+        {
+            Ok(_) => Ok(Some(now + self.lease_duration)),
+            Err(kube::Error::Api(ae)) if ae.code == 409 => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
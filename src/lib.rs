@@ -1,7 +1,11 @@
+pub mod controller;
 pub mod crd;
 pub mod error;
+pub mod leader_election;
+pub mod retry;
 pub mod runner;
 pub mod vault_client;
+pub mod webhook;
 
 #[macro_use]
 extern crate handlebars;
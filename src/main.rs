@@ -1,11 +1,17 @@
-use clap;
-use kube;
-use log;
+use anyhow::anyhow;
 use std::env;
 
+use vault2kube::controller::{self, Controller};
+use vault2kube::leader_election::LeaderElector;
+use vault2kube::retry::Backoff;
 use vault2kube::runner::Runner;
 use vault2kube::vault_client;
 
+const K8S_INCLUSTER_NAMESPACE_FILE: &str =
+    "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+const DEFAULT_KUBE_MAX_RETRIES: u32 = 3;
+
 fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let app = clap::App::new("vault2kube")
@@ -13,16 +19,141 @@ fn main() -> anyhow::Result<()> {
         .about("Copy Vault leased secret to Kubernetes secret")
         .setting(clap::AppSettings::SubcommandRequired)
         .subcommand(
-            clap::SubCommand::with_name("run").about("Run").arg(
-                clap::Arg::with_name("namespace")
-                    .long("namespace")
-                    .short("n")
-                    .takes_value(true)
-                    .required(false)
-                    .help("Restrict namespace to find and execute rules from"),
-            ),
+            clap::SubCommand::with_name("run")
+                .about("Run")
+                .arg(
+                    clap::Arg::with_name("namespace")
+                        .long("namespace")
+                        .short("n")
+                        .takes_value(true)
+                        .required(false)
+                        .help("Restrict namespace to find and execute rules from"),
+                )
+                .arg(
+                    clap::Arg::with_name("revoke-vault-token")
+                        .long("revoke-vault-token")
+                        .takes_value(false)
+                        .help("Revoke the Vault token issued by login on exit; Vault revokes leases acquired with the token together"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("crd")
+                .about("Print CustomResourceDefinition of VaultStoreRule in YAML")
+                .arg(
+                    clap::Arg::with_name("conversion-webhook-service")
+                        .long("conversion-webhook-service")
+                        .takes_value(true)
+                        .required(false)
+                        .help("Service of the conversion webhook in namespace/name format; v2 is served only when given"),
+                )
+                .arg(
+                    clap::Arg::with_name("conversion-webhook-port")
+                        .long("conversion-webhook-port")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("443")
+                        .help("Port of the conversion webhook service"),
+                )
+                .arg(
+                    clap::Arg::with_name("conversion-webhook-ca-file")
+                        .long("conversion-webhook-ca-file")
+                        .takes_value(true)
+                        .required(false)
+                        .help("Path to PEM CA certificates to verify the conversion webhook"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("webhook")
+                .about("Serve ValidatingAdmissionWebhook for VaultStoreRule")
+                .arg(
+                    clap::Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("0.0.0.0:8443")
+                        .help("Address to listen on"),
+                )
+                .arg(
+                    clap::Arg::with_name("tls-cert-file")
+                        .long("tls-cert-file")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Path to a PEM certificate (chain) to serve"),
+                )
+                .arg(
+                    clap::Arg::with_name("tls-key-file")
+                        .long("tls-key-file")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Path to a PEM private key of the certificate"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("controller")
+                .about("Run as a controller; watch rules and execute them on changes and deadlines")
+                .arg(
+                    clap::Arg::with_name("namespace")
+                        .long("namespace")
+                        .short("n")
+                        .takes_value(true)
+                        .required(false)
+                        .help("Restrict namespace to find and execute rules from"),
+                )
+                .arg(
+                    clap::Arg::with_name("resync-interval")
+                        .long("resync-interval")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("3600")
+                        .help("Maximum interval in seconds to execute each rule"),
+                )
+                .arg(
+                    clap::Arg::with_name("leader-elect")
+                        .long("leader-elect")
+                        .takes_value(false)
+                        .help("Enable leader election to run multiple replicas"),
+                )
+                .arg(
+                    clap::Arg::with_name("leader-elect-namespace")
+                        .long("leader-elect-namespace")
+                        .takes_value(true)
+                        .required(false)
+                        .help("Namespace of a Lease object for leader election (default to the namespace of the service account)"),
+                )
+                .arg(
+                    clap::Arg::with_name("leader-elect-lease-name")
+                        .long("leader-elect-lease-name")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("vault2kube")
+                        .help("Name of a Lease object for leader election"),
+                )
+                .arg(
+                    clap::Arg::with_name("leader-elect-lease-duration")
+                        .long("leader-elect-lease-duration")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("15")
+                        .help("Duration in seconds that followers wait before taking over the leadership"),
+                )
+                .arg(
+                    clap::Arg::with_name("leader-elect-identity")
+                        .long("leader-elect-identity")
+                        .takes_value(true)
+                        .required(false)
+                        .help("Identity of this replica for leader election (default to $HOSTNAME)"),
+                )
+                .arg(
+                    clap::Arg::with_name("revoke-vault-token")
+                        .long("revoke-vault-token")
+                        .takes_value(false)
+                        .help("Revoke the Vault token issued by login on exit; Vault revokes leases acquired with the token together"),
+                ),
         );
     let matches = app.get_matches();
+    if let ("crd", Some(crd_command)) = matches.subcommand() {
+        return crd(crd_command);
+    }
     run_subcommand(matches.subcommand())
 }
 
@@ -30,15 +161,156 @@ fn main() -> anyhow::Result<()> {
 async fn run_subcommand(subcommand: (&str, Option<&clap::ArgMatches>)) -> anyhow::Result<()> {
     match subcommand {
         ("run", Some(run_command)) => run(run_command).await,
+        ("controller", Some(controller_command)) => controller(controller_command).await,
+        ("webhook", Some(webhook_command)) => webhook(webhook_command).await,
         _ => panic!("?"),
     }
 }
 
+fn crd(args: &clap::ArgMatches<'_>) -> anyhow::Result<()> {
+    let conversion_webhook = match args.value_of("conversion-webhook-service") {
+        Some(service) => {
+            let mut parts = service.splitn(2, '/');
+            let (namespace, name) = match (parts.next(), parts.next()) {
+                (Some(namespace), Some(name)) => (namespace.to_string(), name.to_string()),
+                _ => {
+                    return Err(anyhow!(
+                        "--conversion-webhook-service must be in namespace/name format"
+                    ))
+                }
+            };
+            let ca_bundle = match args.value_of("conversion-webhook-ca-file") {
+                Some(path) => Some(std::fs::read_to_string(path)?),
+                None => None,
+            };
+            Some(vault2kube::crd::ConversionWebhook {
+                namespace,
+                name,
+                port: args.value_of("conversion-webhook-port").unwrap().parse()?,
+                ca_bundle,
+            })
+        }
+        None => None,
+    };
+    print!(
+        "{}",
+        serde_yaml::to_string(&vault2kube::crd::custom_resource_definition(
+            conversion_webhook.as_ref()
+        ))?
+    );
+    Ok(())
+}
+
 async fn run(args: &clap::ArgMatches<'_>) -> anyhow::Result<()> {
     log::info!("==> Starting...");
     let kube_client = kube::Client::try_default();
     let vault_client = vault_client::Client::new();
-    let namespace = args.value_of("namespace").and_then(|s| Some(s.to_string()));
-    let runner = Runner::new(kube_client.await?, vault_client.await?, namespace);
-    runner.run().await
+    let namespace = args.value_of("namespace").map(|s| s.to_string());
+    let vault_client = vault_client.await?;
+    let kube_backoff = Backoff::from_env("KUBE_MAX_RETRIES", DEFAULT_KUBE_MAX_RETRIES)?;
+    let runner = Runner::new(
+        kube_client.await?,
+        vault_client.clone(),
+        namespace,
+        kube_backoff,
+    );
+    let result = runner.run().await;
+    if args.is_present("revoke-vault-token") {
+        if let Err(e) = vault_client.revoke_token().await {
+            log::warn!("!!!! Failed to revoke Vault token: {}", e);
+        }
+    }
+    result
+}
+
+async fn controller(args: &clap::ArgMatches<'_>) -> anyhow::Result<()> {
+    log::info!("==> Starting...");
+    let kube_client = kube::Client::try_default();
+    let vault_client = vault_client::Client::new();
+    let namespace = args.value_of("namespace").map(|s| s.to_string());
+    let resync_interval: i64 = args.value_of("resync-interval").unwrap().parse()?;
+    let kube_client = kube_client.await?;
+
+    let leadership = if args.is_present("leader-elect") {
+        let lease_namespace = match args.value_of("leader-elect-namespace") {
+            Some(ns) => ns.to_string(),
+            None => std::fs::read_to_string(K8S_INCLUSTER_NAMESPACE_FILE)
+                .map(|ns| ns.trim().to_string())
+                .map_err(|_| {
+                    anyhow!("--leader-elect-namespace must be given when not running in-cluster")
+                })?,
+        };
+        let identity = match args.value_of("leader-elect-identity") {
+            Some(identity) => identity.to_string(),
+            None => env::var("HOSTNAME").map_err(|_| {
+                anyhow!("--leader-elect-identity must be given when HOSTNAME is not present")
+            })?,
+        };
+        let lease_duration: i64 = args
+            .value_of("leader-elect-lease-duration")
+            .unwrap()
+            .parse()?;
+        let elector = LeaderElector::new(
+            kube_client.clone(),
+            &lease_namespace,
+            args.value_of("leader-elect-lease-name")
+                .unwrap()
+                .to_string(),
+            identity,
+            chrono::Duration::seconds(lease_duration),
+            // A single rule may legitimately take this long; a leader stuck longer gives up the leadership
+            chrono::Duration::seconds(controller::RULE_TIMEOUT_SECONDS as i64 + lease_duration),
+        );
+        let leadership = elector.leadership();
+        tokio::spawn(elector.run());
+        Some(leadership)
+    } else {
+        None
+    };
+
+    let vault_client = vault_client.await?;
+    tokio::spawn(vault_client.clone().keep_token_renewed());
+
+    let kube_backoff = Backoff::from_env("KUBE_MAX_RETRIES", DEFAULT_KUBE_MAX_RETRIES)?;
+    let mut controller = Controller::new(
+        kube_client,
+        vault_client.clone(),
+        namespace,
+        kube_backoff,
+        chrono::Duration::seconds(resync_interval),
+        leadership,
+    );
+    let result = tokio::select! {
+        result = controller.run() => result,
+        result = shutdown_signal() => result,
+    };
+    log::info!("==> Shutting down...");
+    if args.is_present("revoke-vault-token") {
+        if let Err(e) = vault_client.revoke_token().await {
+            log::warn!("!!!! Failed to revoke Vault token: {}", e);
+        }
+    }
+    result
+}
+
+async fn webhook(args: &clap::ArgMatches<'_>) -> anyhow::Result<()> {
+    log::info!("==> Starting...");
+    let addr: std::net::SocketAddr = args.value_of("listen").unwrap().parse()?;
+    let identity = vault2kube::webhook::load_identity(
+        args.value_of("tls-cert-file").unwrap(),
+        args.value_of("tls-key-file").unwrap(),
+    )?;
+    tokio::select! {
+        result = vault2kube::webhook::serve(addr, identity) => result,
+        result = shutdown_signal() => result,
+    }
+}
+
+async fn shutdown_signal() -> anyhow::Result<()> {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    };
+    Ok(())
 }
@@ -0,0 +1,75 @@
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+
+const BASE_DELAY_MILLIS: u64 = 500;
+const MAX_DELAY_MILLIS: u64 = 30_000;
+
+/// Exponential backoff with jitter.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Backoff {
+    pub fn new(max_retries: u32) -> Self {
+        Backoff {
+            max_retries,
+            base_delay: Duration::from_millis(BASE_DELAY_MILLIS),
+            max_delay: Duration::from_millis(MAX_DELAY_MILLIS),
+        }
+    }
+
+    /// Takes the maximum number of retries from an environment variable when present.
+    pub fn from_env(name: &str, default_max_retries: u32) -> anyhow::Result<Self> {
+        let max_retries = match std::env::var(name) {
+            Ok(v) => v.parse()?,
+            Err(_e) => default_max_retries,
+        };
+        Ok(Backoff::new(max_retries))
+    }
+
+    /// Delay before the given retry (0-origin); picked randomly from [d/2, d] where d doubles on each attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let max = self.ceiling(attempt).as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(max / 2, max + 1))
+    }
+
+    /// Upper bound of the delay before the given retry (0-origin), without jitter.
+    pub fn ceiling(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// Runs a given function, and retries while it returns an error considered retryable.
+    pub async fn retry<T, E, F, Fut, R>(&self, mut f: F, is_retryable: R) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        R: Fn(&E) -> bool,
+        E: std::fmt::Display,
+    {
+        let mut attempt = 0;
+        loop {
+            match f().await {
+                Err(e) if attempt < self.max_retries && is_retryable(&e) => {
+                    let delay = self.delay(attempt);
+                    attempt += 1;
+                    log::warn!(
+                        "   ! Retrying in {:?} ({}/{}): {}",
+                        delay,
+                        attempt,
+                        self.max_retries,
+                        e
+                    );
+                    tokio::time::delay_for(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
@@ -1,18 +1,35 @@
 use anyhow::anyhow;
 use chrono::{DateTime, Utc};
 use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, StatefulSet};
-use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference, Secret};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
 use log;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use crate::crd::{VaultStoreRule, VaultStoreRuleRollout, VaultStoreRuleStatus};
-use crate::error::Error::{RuleExecutionFailed, UnsupportedRolloutKind};
+use crate::crd::{
+    VaultStoreRule, VaultStoreRuleCondition, VaultStoreRuleRollout, VaultStoreRuleStatus,
+};
+use crate::error::Error::{
+    MissingSecretKey, RuleExecutionFailed, UnsupportedDestinationType, UnsupportedRolloutKind,
+    UnsupportedSourceKind,
+};
+use crate::error::VaultError;
+use crate::retry::Backoff;
 use crate::vault_client;
 
+const FINALIZER: &str = "vault2kube.sorah.jp/cleanup";
+const ANNOTATION_LEASE_ID: &str = "vault2kube.sorah.jp/lease-id";
+const ANNOTATION_EXPIRES_AT: &str = "vault2kube.sorah.jp/expires-at";
+const ANNOTATION_SOURCE_PATH: &str = "vault2kube.sorah.jp/source-path";
+const ANNOTATION_ROTATED_AT: &str = "vault2kube.sorah.jp/rotated-at";
+const EVENT_NORMAL: &str = "Normal";
+const EVENT_WARNING: &str = "Warning";
+
 pub struct Runner {
     kube: kube::Client,
     kube_crd: kube::Api<VaultStoreRule>,
     vault_client: vault_client::Client,
+    kube_backoff: Backoff,
     now: DateTime<Utc>,
 }
 
@@ -21,29 +38,31 @@ impl Runner {
         kube_client: kube::Client,
         vault_client: vault_client::Client,
         namespace: Option<String>,
+        kube_backoff: Backoff,
     ) -> Self {
         let kube_crd: kube::Api<VaultStoreRule> = if let Some(ns) = namespace.clone() {
             kube::Api::namespaced(kube_client.clone(), &ns)
         } else {
             kube::Api::all(kube_client.clone())
         };
-        return Runner {
+        Runner {
             kube: kube_client,
             kube_crd,
             vault_client,
+            kube_backoff,
             now: chrono::Utc::now(),
-        };
+        }
     }
 
     pub async fn run(&self) -> anyhow::Result<()> {
         log::info!("Finding rules...");
-        let mut list = self
+        let list = self
             .kube_crd
             .list(&kube::api::ListParams::default())
             .await?
             .into_iter();
         let mut failed = false;
-        while let Some(rule) = list.next() {
+        for rule in list {
             log::info!("");
             log::info!(
                 "Rule: {}/{}",
@@ -53,10 +72,27 @@ impl Runner {
                     .unwrap_or(&"default".to_string()),
                 rule.metadata.name.as_ref().unwrap(),
             );
+            if let Some(until) = backoff_until(&rule) {
+                log::warn!(
+                    "   ! Skipping; backing off after {} consecutive failures until {:?}",
+                    rule.status
+                        .as_ref()
+                        .and_then(|s| s.consecutive_failures)
+                        .unwrap_or(0),
+                    until
+                );
+                failed = true;
+                continue;
+            }
             let rule_result = self.run_rule(&rule).await;
-            if rule_result.is_err() {
-                log::error!("!!!! Failed: {}", rule_result.err().unwrap());
+            if let Err(e) = rule_result {
+                log_rule_failure(e.as_ref());
                 failed = true;
+                // Subsequent rules will never succeed
+                if let Some(VaultError::Sealed(_)) = e.downcast_ref::<VaultError>() {
+                    log::error!("!!!! Skipping remaining rules as Vault is sealed");
+                    break;
+                }
             }
         }
         if failed {
@@ -67,7 +103,19 @@ impl Runner {
         }
     }
 
-    async fn run_rule(&self, rule: &VaultStoreRule) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn run_rule(
+        &self,
+        rule: &VaultStoreRule,
+    ) -> Result<VaultStoreRuleStatus, Box<dyn std::error::Error>> {
+        if rule.metadata.deletion_timestamp.is_some() {
+            if let Err(e) = self.finalize(rule).await {
+                self.record_event(rule, EVENT_WARNING, "FinalizeFailed", e.to_string())
+                    .await;
+                return Err(e);
+            }
+            return Ok(rule.status.clone().unwrap_or_default());
+        }
+
         log::info!("===> Checking status");
         let mut status = if let Some(status) = rule.status.clone() {
             log::info!("     Status: {:?}", &status);
@@ -76,14 +124,209 @@ impl Runner {
             log::info!("     Status: (new)");
             VaultStoreRuleStatus::default()
         };
-        let lease_not_exists = status.lease_id.is_none();
-        let orig_expires_at = status.expires_at;
-        let orphaned_lease_id = status.next_lease_id.clone();
         let last_successful_run_at = status.last_successful_run_at;
+        // The spec may not be applied to the secret yet when it is changed since the last run, or the last run failed
+        let spec_changed = status.observed_generation != rule.metadata.generation
+            || status.consecutive_failures.unwrap_or(0) > 0;
+        status.last_run_started_at = Some(self.now);
+        status.observed_generation = rule.metadata.generation;
+
+        let mut new_status = status.clone();
+        match self
+            .execute_rule(rule, &mut new_status, last_successful_run_at, spec_changed)
+            .await
+        {
+            Ok(_) => {
+                // update status
+                new_status.next_lease_id = None;
+                new_status.last_successful_run_at = Some(self.now);
+                new_status.consecutive_failures = None;
+                self.set_conditions(rule, &mut new_status, None);
+                self.patch_status(rule, &new_status).await?;
+                log::info!("===> Complete");
+                log::info!("   * Status: {:?}", new_status);
+
+                Ok(new_status)
+            }
+            Err(e) => {
+                self.record_event(rule, EVENT_WARNING, "Failed", e.to_string())
+                    .await;
+
+                // Partial results of a failed run can't be trusted, except a lease acquired in the run to revoke later,
+                // and a lease or data already written to the secret
+                status.next_lease_id = new_status.next_lease_id.clone();
+                if new_status.rollout_pending_since == Some(self.now) {
+                    status.lease_id = new_status.lease_id;
+                    status.last_lease_id = new_status.last_lease_id;
+                    status.ttl = new_status.ttl;
+                    status.expires_at = new_status.expires_at;
+                    status.renewable = new_status.renewable;
+                    status.rotated_at = new_status.rotated_at;
+                    status.source_version = new_status.source_version;
+                    status.source_rotated_at = new_status.source_rotated_at;
+                    status.rollout_pending_since = new_status.rollout_pending_since;
+                    if status.next_lease_id == status.lease_id {
+                        status.next_lease_id = None;
+                    }
+                }
+                status.last_error = Some(e.to_string());
+                status.last_failed_at = Some(self.now);
+                status.consecutive_failures = Some(status.consecutive_failures.unwrap_or(0) + 1);
+                self.set_conditions(rule, &mut status, Some(e.as_ref()));
+                if let Err(pe) = self.patch_status(rule, &status).await {
+                    log::warn!("   ! Failed to update status: {}", pe);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn execute_rule(
+        &self,
+        rule: &VaultStoreRule,
+        status: &mut VaultStoreRuleStatus,
+        last_successful_run_at: Option<DateTime<Utc>>,
+        spec_changed: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.vault_client.renew_token_if_needed().await?;
+        self.add_finalizer(rule).await?;
 
         // record this run
-        status.last_run_started_at = Some(self.now.clone());
-        self.patch_status(&rule, &status).await?;
+        self.patch_status(rule, status).await?;
+
+        match rule.spec.source_kind.as_deref().unwrap_or("lease") {
+            "lease" => {
+                self.run_lease_rule(rule, status, last_successful_run_at)
+                    .await
+            }
+            "kv-v2" => {
+                self.run_kv_v2_rule(rule, status, last_successful_run_at, spec_changed)
+                    .await
+            }
+            "database-static" => {
+                self.run_database_static_rule(rule, status, last_successful_run_at, spec_changed)
+                    .await
+            }
+            _ => Err(Box::new(UnsupportedSourceKind)),
+        }
+    }
+
+    fn set_conditions(
+        &self,
+        rule: &VaultStoreRule,
+        status: &mut VaultStoreRuleStatus,
+        error: Option<&(dyn std::error::Error + 'static)>,
+    ) {
+        let mut conditions = status.conditions.take().unwrap_or_default();
+        match error {
+            None => {
+                set_condition(
+                    &mut conditions,
+                    "Ready",
+                    "True",
+                    "Succeeded",
+                    None,
+                    self.now,
+                );
+                set_condition(
+                    &mut conditions,
+                    "Degraded",
+                    "False",
+                    "Succeeded",
+                    None,
+                    self.now,
+                );
+            }
+            Some(e) => {
+                let message = Some(e.to_string());
+                set_condition(
+                    &mut conditions,
+                    "Ready",
+                    "False",
+                    "Failed",
+                    message.clone(),
+                    self.now,
+                );
+                set_condition(
+                    &mut conditions,
+                    "Degraded",
+                    "True",
+                    "Failed",
+                    message,
+                    self.now,
+                );
+            }
+        }
+
+        match (rule.spec.source_kind.as_deref(), status.expires_at) {
+            (None, Some(expires_at)) | (Some("lease"), Some(expires_at)) => {
+                // The lease stays within the renew window even after this run; renewals no longer keep it fresh
+                let window = rule
+                    .spec
+                    .renew_before_seconds
+                    .into_iter()
+                    .chain(rule.spec.rotate_before_seconds)
+                    .max()
+                    .unwrap_or(0);
+                if expires_at <= self.now + chrono::Duration::seconds(window as i64) {
+                    let message = Some(format!(
+                        "Lease expires at {}, before the next possible renew",
+                        expires_at.to_rfc3339()
+                    ));
+                    set_condition(
+                        &mut conditions,
+                        "LeaseExpiringSoon",
+                        "True",
+                        "WithinRenewWindow",
+                        message,
+                        self.now,
+                    );
+                } else {
+                    set_condition(
+                        &mut conditions,
+                        "LeaseExpiringSoon",
+                        "False",
+                        "LeaseValid",
+                        None,
+                        self.now,
+                    );
+                }
+            }
+            _ => conditions.retain(|c| c.type_ != "LeaseExpiringSoon"),
+        }
+        status.conditions = Some(conditions);
+    }
+
+    async fn run_lease_rule(
+        &self,
+        rule: &VaultStoreRule,
+        status: &mut VaultStoreRuleStatus,
+        last_successful_run_at: Option<DateTime<Utc>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if status.lease_id.is_none()
+            && status.last_lease_id.is_none()
+            && status.next_lease_id.is_none()
+            && status.last_successful_run_at.is_none()
+        {
+            self.adopt_lease(rule, status).await?;
+        }
+
+        // reconcile with Vault, as leases may be renewed or revoked elsewhere
+        let looked_up_lease_id = status.lease_id.clone();
+        self.lookup(status).await;
+        if let (Some(lease_id), None) = (&looked_up_lease_id, &status.lease_id) {
+            self.record_event(
+                rule,
+                EVENT_WARNING,
+                "LeaseLost",
+                format!("Lease {} is lost in Vault; rotating", lease_id),
+            )
+            .await;
+        }
+
+        let lease_not_exists = status.lease_id.is_none();
+        let orig_expires_at = status.expires_at;
+        let orphaned_lease_id = status.next_lease_id.clone();
 
         // discard an expired lease when present
         let needs_discard = orig_expires_at
@@ -96,7 +339,7 @@ impl Runner {
             );
         }
 
-        let needs_rotate = lease_not_exists
+        let mut needs_rotate = lease_not_exists
             || needs_discard
             || check_request_annotation(
                 last_successful_run_at.as_ref(),
@@ -119,20 +362,106 @@ impl Runner {
             rule.spec.renew_before_seconds,
         );
 
-        // try revoke
+        // A missing secret (e.g. deleted by hand, or a failure after deleting it for a type change) can't be rendered
+        // again without credentials; acquire a fresh lease to re-create it
+        if !needs_rotate && self.get_secret(rule).await?.is_none() {
+            log::warn!(
+                "   ! Secret {} is missing, will rotate to re-create",
+                &rule.spec.destination_name
+            );
+            needs_rotate = true;
+        }
+
+        // non-renewable leases (e.g. AWS STS credentials) never succeed to renew; rotate instead
+        if needs_renew && !needs_rotate && status.renewable == Some(false) {
+            log::info!(
+                "   * The current lease={:?} is not renewable, will rotate instead of renew",
+                &status.lease_id
+            );
+            needs_rotate = true;
+        }
+
+        // renew
+        if !needs_discard && !needs_rotate && needs_renew {
+            let lease_id = status.lease_id.clone().unwrap_or_default();
+            let original_ttl = status.ttl;
+            let renew_result = self.renew(status).await;
+            match &renew_result {
+                Ok(true) => {
+                    self.record_event(
+                        rule,
+                        EVENT_NORMAL,
+                        "RenewCapped",
+                        format!(
+                            "Renewed lease {}, but ttl is capped to max_ttl (original_ttl={}, ttl={}); rotating",
+                            &lease_id,
+                            original_ttl.unwrap_or_default(),
+                            status.ttl.unwrap_or_default()
+                        ),
+                    )
+                    .await;
+                    // Rotate right now, as the capped lease may expire before the next run
+                    needs_rotate = true;
+                }
+                Ok(false) => {
+                    self.record_event(
+                        rule,
+                        EVENT_NORMAL,
+                        "Renewed",
+                        format!(
+                            "Renewed lease {} (ttl={})",
+                            &lease_id,
+                            status.ttl.unwrap_or_default()
+                        ),
+                    )
+                    .await;
+                    self.annotate_secret(rule, status).await;
+                }
+                Err(_) => {}
+            }
+            if let Err(e) = renew_result {
+                // The lease may be revoked out of band or lost in Vault; renew never succeeds, so acquire a fresh one
+                match e.downcast_ref::<VaultError>() {
+                    Some(VaultError::LeaseNotFound(errors)) => {
+                        log::warn!(
+                            "   ! The current lease={:?} seems to be lost ({}), will rotate",
+                            &status.lease_id,
+                            errors.join(", ")
+                        );
+                        // Nothing to revoke later
+                        status.lease_id = None;
+                        status.lease_lost_at = Some(self.now);
+                        needs_rotate = true;
+                        self.record_event(
+                            rule,
+                            EVENT_WARNING,
+                            "LeaseLost",
+                            format!("Lease {} is lost in Vault; rotating", &lease_id),
+                        )
+                        .await;
+                    }
+                    _ => return Err(e),
+                }
+            }
+        }
+
+        // try revoke; after renew, as a capped or lost lease requires a rotation
         if status.last_lease_id.is_some()
             && (is_time_after_deadline(
                 &self.now,
                 &status.rotated_at,
-                rule.spec.revoke_after_seconds.and_then(|s| Some(s * -1)),
+                rule.spec.revoke_after_seconds.map(|s| -s),
             ) || needs_rotate)
         {
-            self.revoke_last(&mut status).await?;
-        }
-
-        // renew
-        if !needs_discard && !needs_rotate && needs_renew {
-            self.renew(&mut status).await?;
+            let lease_id = status.last_lease_id.clone();
+            self.revoke_last(status).await?;
+            self.record_event(
+                rule,
+                EVENT_NORMAL,
+                "Revoked",
+                format!("Revoked the last lease {}", lease_id.unwrap_or_default()),
+            )
+            .await;
         }
 
         // rotate
@@ -141,45 +470,468 @@ impl Runner {
                 .rotate(
                     &rule.spec.source_path,
                     rule.spec.parameters.as_ref(),
-                    &mut status,
+                    status,
                 )
                 .await?;
 
             // Save the fresh lease_id as soon as possible, to easily revoke them later in case of any failure
             // may occur in the same run.
-            self.patch_status_next_lease_id(&rule, Some(lease.lease_id.to_owned().as_ref()))
+            self.patch_status_next_lease_id(rule, Some(lease.lease_id.to_owned().as_ref()))
                 .await?;
+            status.next_lease_id = Some(lease.lease_id.clone());
 
-            self.update_secret(&rule, lease).await?;
+            let lease_context = serde_json::json!({
+                "lease_id": &lease.lease_id,
+                "lease_duration": lease.lease_duration,
+                "renewable": lease.renewable,
+                "expires_at": status.expires_at,
+                "request_id": &lease.request_id,
+            });
+            self.update_secret(
+                rule,
+                &template_context(rule, &lease.data, Some(lease_context)),
+            )
+            .await?;
+            status.rollout_pending_since = Some(self.now);
 
-            self.patch_status_next_lease_id(&rule, None).await?;
+            self.patch_status_next_lease_id(rule, None).await?;
+            status.next_lease_id = None;
+            self.annotate_secret(rule, status).await;
+            self.record_event(
+                rule,
+                EVENT_NORMAL,
+                "LeaseAcquired",
+                format!(
+                    "Acquired lease {} (ttl={}) and updated secret {}",
+                    &lease.lease_id, lease.lease_duration, &rule.spec.destination_name
+                ),
+            )
+            .await;
         }
 
         // rollout
-        if needs_rotate {
-            if let Some(rollout_resources) = rule.spec.rollout_restarts.clone() {
-                let namespace = rule
-                    .metadata
-                    .namespace
-                    .clone()
-                    .unwrap_or("default".to_string());
-                self.rollout(&namespace, &rollout_resources).await?;
-            }
-        }
+        self.rollout_restarts(rule, status).await?;
 
         // revoke unused secret due to possible failure
         if let Some(lease_id) = orphaned_lease_id {
             log::warn!("   * Revoking orphaned lease_id={:?}", lease_id);
-            self.vault_client.revoke(&lease_id).await?;
+            self.revoke_lease(&lease_id).await?;
+            self.record_event(
+                rule,
+                EVENT_NORMAL,
+                "Revoked",
+                format!("Revoked orphaned lease {}", &lease_id),
+            )
+            .await;
+        }
+
+        Ok(())
+    }
+
+    // KV v2 secrets have no lease; re-sync only when a version or the spec is changed
+    async fn run_kv_v2_rule(
+        &self,
+        rule: &VaultStoreRule,
+        status: &mut VaultStoreRuleStatus,
+        last_successful_run_at: Option<DateTime<Utc>>,
+        spec_changed: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        log::info!(
+            "===> Reading KV v2 secret at path={:?}",
+            &rule.spec.source_path
+        );
+        let response = self.vault_client.read(&rule.spec.source_path).await?;
+        let version = response
+            .data
+            .get("metadata")
+            .and_then(|m| m.get("version"))
+            .and_then(|v| v.as_u64());
+        let data: HashMap<String, serde_json::Value> = match response.data.get("data") {
+            Some(serde_json::Value::Object(data)) => data.clone().into_iter().collect(),
+            _ => return Err(
+                "KV v2 response has no data.data; is sourcePath pointing to <mount>/data/<path>?"
+                    .into(),
+            ),
+        };
+        log::info!(
+            "   * version={:?} (last synced version={:?})",
+            &version,
+            &status.source_version
+        );
+
+        let requested = check_request_annotation(
+            last_successful_run_at.as_ref(),
+            rule.metadata
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get("vault2kube.sorah.jp/rotateRequestedAt")),
+        );
+        if version.is_some() && version == status.source_version && !requested && !spec_changed {
+            if self.get_secret(rule).await?.is_some() {
+                log::info!("   * Unchanged, skipping");
+                return self.rollout_restarts(rule, status).await;
+            }
+            log::warn!(
+                "   ! Secret {} is missing, re-creating",
+                &rule.spec.destination_name
+            );
+        }
+
+        self.update_secret(rule, &template_context(rule, &data, None))
+            .await?;
+        // Rollout only on changes; the first sync merely creates a secret
+        if status.source_version.is_some() && version != status.source_version {
+            status.rollout_pending_since = Some(self.now);
+        }
+        status.source_version = version;
+        status.rotated_at = Some(self.now);
+        self.annotate_secret(rule, status).await;
+        self.record_event(
+            rule,
+            EVENT_NORMAL,
+            "Synced",
+            format!(
+                "Updated secret {} with version {}",
+                &rule.spec.destination_name,
+                version.unwrap_or_default()
+            ),
+        )
+        .await;
+
+        self.rollout_restarts(rule, status).await
+    }
+
+    // Database static roles have no lease; Vault rotates a password in place every rotation_period
+    async fn run_database_static_rule(
+        &self,
+        rule: &VaultStoreRule,
+        status: &mut VaultStoreRuleStatus,
+        last_successful_run_at: Option<DateTime<Utc>>,
+        spec_changed: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        log::info!(
+            "===> Reading static credentials at path={:?}",
+            &rule.spec.source_path
+        );
+        let response = self.vault_client.read(&rule.spec.source_path).await?;
+        let last_vault_rotation = response
+            .data
+            .get("last_vault_rotation")
+            .and_then(|v| v.as_str())
+            .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+            .map(|t| t.with_timezone(&Utc));
+        let rotation_period = response
+            .data
+            .get("rotation_period")
+            .and_then(|v| v.as_i64());
+        let ttl = response.data.get("ttl").and_then(|v| v.as_i64());
+        log::info!(
+            "   * last_vault_rotation={:?}, rotation_period={:?}, ttl={:?} (last synced rotation={:?})",
+            &last_vault_rotation,
+            &rotation_period,
+            &ttl,
+            &status.source_rotated_at
+        );
+
+        // Re-read when Vault is going to rotate the password next time
+        status.expires_at = match (last_vault_rotation, rotation_period, ttl) {
+            (Some(t), Some(period), _) if period > 0 => Some(t + chrono::Duration::seconds(period)),
+            (_, _, Some(ttl)) => Some(self.now + chrono::Duration::seconds(ttl)),
+            _ => None,
+        };
+        status.ttl = ttl.map(|t| t as u32);
+
+        let requested = check_request_annotation(
+            last_successful_run_at.as_ref(),
+            rule.metadata
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get("vault2kube.sorah.jp/rotateRequestedAt")),
+        );
+        if last_vault_rotation.is_some()
+            && last_vault_rotation == status.source_rotated_at
+            && !requested
+            && !spec_changed
+        {
+            if self.get_secret(rule).await?.is_some() {
+                log::info!("   * Unchanged, skipping");
+                return self.rollout_restarts(rule, status).await;
+            }
+            log::warn!(
+                "   ! Secret {} is missing, re-creating",
+                &rule.spec.destination_name
+            );
         }
 
-        // update status
-        status.next_lease_id = None;
-        status.last_successful_run_at = Some(self.now.clone());
-        self.patch_status(&rule, &status).await?;
-        log::info!("===> Complete");
-        log::info!("   * Status: {:?}", status);
+        let lease_context = serde_json::json!({
+            "expires_at": status.expires_at,
+            "request_id": &response.request_id,
+        });
+        self.update_secret(
+            rule,
+            &template_context(rule, &response.data, Some(lease_context)),
+        )
+        .await?;
+        // Rollout only on password rotations; the first sync merely creates a secret
+        if status.source_rotated_at.is_some() && last_vault_rotation != status.source_rotated_at {
+            status.rollout_pending_since = Some(self.now);
+        }
+        status.source_rotated_at = last_vault_rotation;
+        status.rotated_at = Some(self.now);
+        self.annotate_secret(rule, status).await;
+        self.record_event(
+            rule,
+            EVENT_NORMAL,
+            "Synced",
+            format!(
+                "Updated secret {} with credentials rotated at {:?}",
+                &rule.spec.destination_name, &last_vault_rotation
+            ),
+        )
+        .await;
+
+        self.rollout_restarts(rule, status).await
+    }
 
+    // Performs rollout restarts requested by an update of the secret, including ones failed in previous runs
+    async fn rollout_restarts(
+        &self,
+        rule: &VaultStoreRule,
+        status: &mut VaultStoreRuleStatus,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if status.rollout_pending_since.is_none() {
+            return Ok(());
+        }
+        if let Some(rollout_resources) = rule.spec.rollout_restarts.clone() {
+            let namespace = rule
+                .metadata
+                .namespace
+                .clone()
+                .unwrap_or("default".to_string());
+            self.rollout(&namespace, &rollout_resources).await?;
+            let names: Vec<String> = rollout_resources
+                .iter()
+                .map(|r| format!("{}/{}", &r.kind, &r.name))
+                .collect();
+            self.record_event(
+                rule,
+                EVENT_NORMAL,
+                "RolloutRestarted",
+                format!("Triggered rollout restart of {}", names.join(", ")),
+            )
+            .await;
+        }
+        status.rollout_pending_since = None;
+        Ok(())
+    }
+
+    // Events are informational; failing to record them never fails a rule
+    async fn record_event(
+        &self,
+        rule: &VaultStoreRule,
+        type_: &str,
+        reason: &str,
+        message: String,
+    ) {
+        let namespace = rule
+            .metadata
+            .namespace
+            .clone()
+            .unwrap_or("default".to_string());
+        let events: kube::Api<Event> = kube::Api::namespaced(self.kube.clone(), &namespace);
+        let name = rule.metadata.name.clone().unwrap_or_default();
+        let now = Time(Utc::now());
+        let event = Event {
+            metadata: ObjectMeta {
+                generate_name: Some(format!("{}.", &name)),
+                ..Default::default()
+            },
+            involved_object: ObjectReference {
+                api_version: Some("vault2kube.sorah.jp/v1".to_string()),
+                kind: Some("VaultStoreRule".to_string()),
+                name: Some(name),
+                namespace: Some(namespace),
+                uid: rule.metadata.uid.clone(),
+                resource_version: rule.metadata.resource_version.clone(),
+                ..Default::default()
+            },
+            type_: Some(type_.to_string()),
+            reason: Some(reason.to_string()),
+            message: Some(message),
+            source: Some(EventSource {
+                component: Some("vault2kube".to_string()),
+                ..Default::default()
+            }),
+            first_timestamp: Some(now.clone()),
+            last_timestamp: Some(now),
+            count: Some(1),
+            ..Default::default()
+        };
+        if let Err(e) = events
+            .create(&kube::api::PostParams::default(), &event)
+            .await
+        {
+            log::warn!("   ! Failed to record event ({}): {}", reason, e);
+        }
+    }
+
+    async fn add_finalizer(&self, rule: &VaultStoreRule) -> Result<(), Box<dyn std::error::Error>> {
+        let mut finalizers = rule.metadata.finalizers.clone().unwrap_or_default();
+        if finalizers.iter().any(|f| f == FINALIZER) {
+            return Ok(());
+        }
+        log::info!("===> Adding finalizer");
+        finalizers.push(FINALIZER.to_string());
+        self.patch_finalizers(rule, finalizers).await
+    }
+
+    // Cleans up leases and the destination secret of a deleted rule, then lets Kubernetes delete the rule
+    async fn finalize(&self, rule: &VaultStoreRule) -> Result<(), Box<dyn std::error::Error>> {
+        let finalizers = rule.metadata.finalizers.clone().unwrap_or_default();
+        if !finalizers.iter().any(|f| f == FINALIZER) {
+            log::info!("===> Rule is being deleted, nothing to do");
+            return Ok(());
+        }
+        log::info!("===> Finalizing the deleted rule");
+        self.vault_client.renew_token_if_needed().await?;
+
+        let status = rule.status.clone().unwrap_or_default();
+        for lease_id in vec![status.lease_id, status.last_lease_id, status.next_lease_id]
+            .into_iter()
+            .flatten()
+        {
+            log::info!("   * Revoking lease={:?}", &lease_id);
+            self.revoke_lease(&lease_id).await?;
+        }
+
+        if rule.spec.secret_deletion_policy.as_deref() == Some("Delete") {
+            self.delete_secret(rule).await?;
+        } else {
+            self.release_secret(rule).await?;
+        }
+
+        log::info!("   * Removing finalizer");
+        self.patch_finalizers(
+            rule,
+            finalizers.into_iter().filter(|f| f != FINALIZER).collect(),
+        )
+        .await
+    }
+
+    async fn patch_finalizers(
+        &self,
+        rule: &VaultStoreRule,
+        finalizers: Vec<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let kube_crd: kube::Api<VaultStoreRule> = kube::Api::namespaced(
+            self.kube.clone(),
+            rule.metadata
+                .namespace
+                .as_ref()
+                .unwrap_or(&"default".to_string()),
+        );
+
+        // finalizers may be modified by others; resourceVersion makes concurrent updates fail with a conflict
+        let patch = serde_json::to_vec(&serde_json::json!({
+            "metadata": {
+                "finalizers": finalizers,
+                "resourceVersion": &rule.metadata.resource_version,
+            },
+        }))?;
+
+        let name = rule.metadata.name.as_ref().ok_or("name is missing")?;
+        let params = kube::api::PatchParams {
+            patch_strategy: kube::api::PatchStrategy::Merge,
+            ..Default::default()
+        };
+        self.kube_backoff
+            .retry(
+                || kube_crd.patch(name, &params, patch.clone()),
+                is_kube_error_retryable,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_secret(&self, rule: &VaultStoreRule) -> Result<(), Box<dyn std::error::Error>> {
+        log::info!("   * Deleting secret: {}", &rule.spec.destination_name);
+        let secrets: kube::Api<Secret> = kube::Api::namespaced(
+            self.kube.clone(),
+            rule.metadata
+                .namespace
+                .as_ref()
+                .unwrap_or(&"default".to_string()),
+        );
+        let params = kube::api::DeleteParams::default();
+        match self
+            .kube_backoff
+            .retry(
+                || secrets.delete(&rule.spec.destination_name, &params),
+                is_kube_error_retryable,
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(ae)) if ae.code == 404 => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    // Removes the owner reference to a deleted rule, to prevent garbage collection from deleting the retained secret
+    async fn release_secret(
+        &self,
+        rule: &VaultStoreRule,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secrets: kube::Api<Secret> = kube::Api::namespaced(
+            self.kube.clone(),
+            rule.metadata
+                .namespace
+                .as_ref()
+                .unwrap_or(&"default".to_string()),
+        );
+        let secret = match self
+            .kube_backoff
+            .retry(
+                || secrets.get(&rule.spec.destination_name),
+                is_kube_error_retryable,
+            )
+            .await
+        {
+            Ok(secret) => secret,
+            Err(kube::Error::Api(ae)) if ae.code == 404 => return Ok(()),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let owner_references = secret.metadata.owner_references.unwrap_or_default();
+        if !owner_references
+            .iter()
+            .any(|r| Some(&r.uid) == rule.metadata.uid.as_ref())
+        {
+            return Ok(());
+        }
+        log::info!(
+            "   * Removing owner reference from secret: {}",
+            &rule.spec.destination_name
+        );
+        let remaining: Vec<_> = owner_references
+            .into_iter()
+            .filter(|r| Some(&r.uid) != rule.metadata.uid.as_ref())
+            .collect();
+        let patch = serde_json::to_vec(&serde_json::json!({
+            "metadata": {
+                "ownerReferences": remaining,
+                "resourceVersion": &secret.metadata.resource_version,
+            },
+        }))?;
+        let params = kube::api::PatchParams {
+            patch_strategy: kube::api::PatchStrategy::Merge,
+            ..Default::default()
+        };
+        self.kube_backoff
+            .retry(
+                || secrets.patch(&rule.spec.destination_name, &params, patch.clone()),
+                is_kube_error_retryable,
+            )
+            .await?;
         Ok(())
     }
 
@@ -192,8 +944,7 @@ impl Runner {
 
         let kube_crd: kube::Api<VaultStoreRule> = kube::Api::namespaced(
             self.kube.clone(),
-            &rule
-                .metadata
+            rule.metadata
                 .namespace
                 .as_ref()
                 .unwrap_or(&"default".to_string()),
@@ -205,11 +956,12 @@ impl Runner {
             "status": status
         }))?;
 
-        kube_crd
-            .patch_status(
-                rule.metadata.name.as_ref().ok_or("name is missing")?,
-                &kube::api::PatchParams::apply("vault2kube.sorah.jp").force(),
-                patch,
+        let name = rule.metadata.name.as_ref().ok_or("name is missing")?;
+        let params = kube::api::PatchParams::apply("vault2kube.sorah.jp").force();
+        self.kube_backoff
+            .retry(
+                || kube_crd.patch_status(name, &params, patch.clone()),
+                is_kube_error_retryable,
             )
             .await?;
         Ok(())
@@ -225,8 +977,7 @@ impl Runner {
 
         let kube_crd: kube::Api<VaultStoreRule> = kube::Api::namespaced(
             self.kube.clone(),
-            &rule
-                .metadata
+            rule.metadata
                 .namespace
                 .as_ref()
                 .unwrap_or(&"default".to_string()),
@@ -241,32 +992,221 @@ impl Runner {
         // Intentionally force
         // https://v1-17.docs.kubernetes.io/docs/reference/using-api/api-concepts/#conflicts
         // https://github.com/cybozu-go/cke/issues/311
-        kube_crd
-            .patch_status(
-                rule.metadata.name.as_ref().ok_or("name is missing")?,
-                &kube::api::PatchParams::apply("vault2kube.sorah.jp").force(),
-                patch,
+        let name = rule.metadata.name.as_ref().ok_or("name is missing")?;
+        let params = kube::api::PatchParams::apply("vault2kube.sorah.jp").force();
+        self.kube_backoff
+            .retry(
+                || kube_crd.patch_status(name, &params, patch.clone()),
+                is_kube_error_retryable,
             )
             .await?;
         Ok(())
     }
 
+    // Updates status with leases on Vault. Failures other than missing leases are not fatal, as status still works
+    // as a fallback.
+    async fn lookup(&self, status: &mut VaultStoreRuleStatus) {
+        if let Some(lease_id) = status.lease_id.clone() {
+            log::info!("===> Looking up the current lease={:?}", &lease_id);
+            match self.vault_client.lookup(&lease_id).await {
+                Ok(lease) => {
+                    set_lease_from_lookup(status, lease.data);
+                    log::info!(
+                        "   * ttl={:?}, expires_at={:?}, renewable={:?}",
+                        &status.ttl,
+                        &status.expires_at,
+                        &status.renewable
+                    );
+                }
+                Err(VaultError::LeaseNotFound(errors)) => {
+                    log::warn!(
+                        "   ! The current lease={:?} seems to be lost ({})",
+                        &lease_id,
+                        errors.join(", ")
+                    );
+                    status.lease_id = None;
+                    status.ttl = None;
+                    status.expires_at = None;
+                    status.renewable = None;
+                    status.lease_lost_at = Some(self.now);
+                }
+                Err(e) => log::warn!("   ! Failed to look up lease={:?}: {}", &lease_id, e),
+            }
+        }
+
+        if let Some(lease_id) = status.last_lease_id.clone() {
+            match self.vault_client.lookup(&lease_id).await {
+                Ok(_) => {}
+                Err(VaultError::LeaseNotFound(_)) => {
+                    log::info!(
+                        "   * The last lease={:?} is already gone, no need to revoke",
+                        &lease_id
+                    );
+                    status.last_lease_id = None;
+                }
+                Err(e) => log::warn!("   ! Failed to look up lease={:?}: {}", &lease_id, e),
+            }
+        }
+    }
+
+    // A rule without status may have been deleted and re-created; take over the lease recorded on its secret rather
+    // than orphaning it, or revoke it when it doesn't belong to the rule anymore.
+    async fn adopt_lease(
+        &self,
+        rule: &VaultStoreRule,
+        status: &mut VaultStoreRuleStatus,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secrets: kube::Api<Secret> = kube::Api::namespaced(
+            self.kube.clone(),
+            rule.metadata
+                .namespace
+                .as_ref()
+                .unwrap_or(&"default".to_string()),
+        );
+        let secret = match self
+            .kube_backoff
+            .retry(
+                || secrets.get(&rule.spec.destination_name),
+                is_kube_error_retryable,
+            )
+            .await
+        {
+            Ok(secret) => secret,
+            Err(kube::Error::Api(ae)) if ae.code == 404 => return Ok(()),
+            Err(e) => return Err(Box::new(e)),
+        };
+        // Leave secrets managed by other rules
+        let labels = secret.metadata.labels.unwrap_or_default();
+        if labels.get("vault2kube.sorah.jp/rule") != rule.metadata.name.as_ref() {
+            return Ok(());
+        }
+        let annotations = secret.metadata.annotations.unwrap_or_default();
+        let lease_id = match annotations.get(ANNOTATION_LEASE_ID) {
+            Some(lease_id) => lease_id.clone(),
+            None => return Ok(()),
+        };
+
+        if annotations.get(ANNOTATION_SOURCE_PATH) != Some(&rule.spec.source_path) {
+            log::warn!(
+                "===> Revoking lease={:?} found on the secret, as it was acquired from a different path",
+                &lease_id
+            );
+            self.revoke_lease(&lease_id).await?;
+            self.record_event(
+                rule,
+                EVENT_NORMAL,
+                "Revoked",
+                format!("Revoked lease {} found on the secret", &lease_id),
+            )
+            .await;
+            return Ok(());
+        }
+
+        match self.vault_client.lookup(&lease_id).await {
+            Ok(lease) => {
+                log::info!("===> Adopting lease={:?} found on the secret", &lease_id);
+                status.lease_id = Some(lease_id.clone());
+                status.rotated_at = annotations
+                    .get(ANNOTATION_ROTATED_AT)
+                    .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                    .map(|t| t.with_timezone(&Utc));
+                set_lease_from_lookup(status, lease.data);
+                self.record_event(
+                    rule,
+                    EVENT_NORMAL,
+                    "Adopted",
+                    format!("Adopted lease {} found on the secret", &lease_id),
+                )
+                .await;
+            }
+            Err(VaultError::LeaseNotFound(_)) => {
+                log::info!(
+                    "   * lease={:?} found on the secret is already gone",
+                    &lease_id
+                );
+            }
+            Err(e) => return Err(Box::new(e)),
+        }
+        Ok(())
+    }
+
+    // Records lease metadata on the secret. Applied with a separate field manager from update_secret, so these can be
+    // updated on renewals without rendering the secret again. Annotations are informational; failing to record them
+    // never fails a rule, as the secret and status are already updated.
+    async fn annotate_secret(&self, rule: &VaultStoreRule, status: &VaultStoreRuleStatus) {
+        let secrets: kube::Api<Secret> = kube::Api::namespaced(
+            self.kube.clone(),
+            rule.metadata
+                .namespace
+                .as_ref()
+                .unwrap_or(&"default".to_string()),
+        );
+        let mut annotations: BTreeMap<&str, String> = BTreeMap::new();
+        annotations.insert(ANNOTATION_SOURCE_PATH, rule.spec.source_path.clone());
+        if let Some(lease_id) = &status.lease_id {
+            annotations.insert(ANNOTATION_LEASE_ID, lease_id.clone());
+        }
+        if let Some(expires_at) = status.expires_at {
+            annotations.insert(ANNOTATION_EXPIRES_AT, expires_at.to_rfc3339());
+        }
+        if let Some(rotated_at) = status.rotated_at {
+            annotations.insert(ANNOTATION_ROTATED_AT, rotated_at.to_rfc3339());
+        }
+        let patch = serde_yaml::to_vec(&serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Secret",
+            "metadata": {
+                "name": &rule.spec.destination_name,
+                "annotations": annotations,
+            },
+        }))
+        .expect("serializable annotations");
+
+        let params = kube::api::PatchParams::apply("vault2kube.sorah.jp/lease").force();
+        if let Err(e) = self
+            .kube_backoff
+            .retry(
+                || secrets.patch(&rule.spec.destination_name, &params, patch.clone()),
+                is_kube_error_retryable,
+            )
+            .await
+        {
+            log::warn!("   ! Failed to annotate secret: {}", e);
+        }
+    }
+
     async fn revoke_last(
         &self,
         status: &mut VaultStoreRuleStatus,
     ) -> Result<(), Box<dyn std::error::Error>> {
         log::warn!("===> Revoking the last lease={:?}", &status.last_lease_id);
-        self.vault_client
-            .revoke(status.last_lease_id.as_ref().unwrap())
+        self.revoke_lease(status.last_lease_id.as_ref().unwrap())
             .await?;
         status.last_lease_id = None;
         Ok(())
     }
 
+    // Revoke a lease, treating a lease already gone from Vault as revoked
+    async fn revoke_lease(&self, lease_id: &str) -> Result<(), VaultError> {
+        match self.vault_client.revoke(lease_id).await {
+            Ok(_) => Ok(()),
+            Err(VaultError::LeaseNotFound(errors)) => {
+                log::warn!(
+                    "   * lease={:?} seems to be already gone: {}",
+                    lease_id,
+                    errors.join(", ")
+                );
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Returns true when the renewed ttl is capped to max_ttl, which requires a rotation
     async fn renew(
         &self,
         status: &mut VaultStoreRuleStatus,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<bool, Box<dyn std::error::Error>> {
         log::info!("===> Renewing the current lease={:?}", &status.lease_id);
         let original_ttl = status.ttl.unwrap();
         let lease = self
@@ -274,6 +1214,7 @@ impl Runner {
             .renew(status.lease_id.as_ref().unwrap())
             .await?;
         status.ttl = Some(lease.lease_duration);
+        status.renewable = Some(lease.renewable);
         status.expires_at =
             Some(Utc::now() + chrono::Duration::seconds(status.ttl.unwrap() as i64));
 
@@ -283,16 +1224,12 @@ impl Runner {
             &status.expires_at
         );
 
-        // In case ttl is capped to max_ttl
+        // In case ttl is capped to max_ttl; the lease stays current until rotated, then revoked as the last lease
         if lease.lease_duration < original_ttl {
             log::info!("   * Renewed, but seems to be capped to max_ttl. Will rotate. (original_ttl={:?}, ttl={:?})", &original_ttl, &lease.lease_duration);
-            status.lease_id = None;
-            status.ttl = None;
-            status.expires_at = None;
-            status.last_lease_id = status.lease_id.clone();
-            status.rotated_at = Some(self.now.clone());
+            return Ok(true);
         }
-        Ok(())
+        Ok(false)
     }
 
     async fn rotate(
@@ -305,9 +1242,9 @@ impl Runner {
         let lease = match parameters {
             Some(params) => {
                 log::info!("   * params: {:}", serde_json::json! {params});
-                self.vault_client.write(&source_path, &params).await?
+                self.vault_client.write(source_path, params).await?
             }
-            None => self.vault_client.read(&source_path).await?,
+            None => self.vault_client.read(source_path).await?,
         };
         log::trace!("lease_response={:}", serde_json::json! {lease.clone()});
         if let Some(original_lease_id) = status.lease_id.clone() {
@@ -316,17 +1253,19 @@ impl Runner {
         }
         status.lease_id = Some(lease.lease_id.clone());
         status.ttl = Some(lease.lease_duration);
-        if status.lease_id.as_ref().unwrap().len() == 0 {
+        status.renewable = Some(lease.renewable);
+        if status.lease_id.as_ref().unwrap().is_empty() {
             log::warn!("   ! lease_id is empty... some secret backends may not generate lease by default (for instance, PKI role has `generate_lease` parameter)");
             status.lease_id = None;
         }
         status.expires_at =
             Some(Utc::now() + chrono::Duration::seconds(status.ttl.unwrap() as i64));
         log::info!(
-            "   * Lease acquired: lease_id={:?}, ttl={:?}, expires_at={:?}",
+            "   * Lease acquired: lease_id={:?}, ttl={:?}, expires_at={:?}, renewable={:?}",
             &status.lease_id,
             &status.ttl,
-            &status.expires_at
+            &status.expires_at,
+            &status.renewable
         );
 
         Ok(lease)
@@ -335,7 +1274,7 @@ impl Runner {
     async fn update_secret(
         &self,
         rule: &VaultStoreRule,
-        lease: vault_client::LeaseResponse,
+        data: &HashMap<String, serde_json::Value>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         log::info!("===> Applying secret: {}", &rule.spec.destination_name);
         let default_ns = "default".to_string();
@@ -344,41 +1283,79 @@ impl Runner {
         let secrets: kube::Api<Secret> = kube::Api::namespaced(self.kube.clone(), namespace);
 
         let mut string_data: HashMap<String, String> = HashMap::new();
-        let mut iter = rule.spec.templates.iter();
+        let iter = rule.spec.templates.iter();
 
         let mut hb = handlebars::Handlebars::new();
         hb.register_escape_fn(handlebars::no_escape);
         hb.register_helper("base64Encode", Box::new(crate::helpers::base64_encode));
         hb.register_helper("base64Decode", Box::new(crate::helpers::base64_decode));
 
-        while let Some(tmpl) = iter.next() {
+        for tmpl in iter {
             log::info!("   * key={:?}, template={:?}", &tmpl.key, &tmpl.template,);
-            let value = hb.render_template(&tmpl.template, &lease.data)?;
+            let value = hb.render_template(&tmpl.template, data)?;
             string_data.insert(tmpl.key.to_owned(), value);
         }
 
-        let patch = serde_json::json!({
+        let secret_type = rule.spec.destination_type.as_deref().unwrap_or("Opaque");
+        let keys: Vec<&str> = string_data.keys().map(|k| k.as_str()).collect();
+        check_destination_type(secret_type, &keys)?;
+
+        // Labels set by vault2kube take precedence over ones given in a rule
+        let mut labels: BTreeMap<String, String> = BTreeMap::new();
+        for (name, tmpl) in rule.spec.destination_labels.iter().flatten() {
+            labels.insert(name.to_owned(), hb.render_template(tmpl, data)?);
+        }
+        labels.insert(
+            "kubernetes.io/managed-by".to_string(),
+            "vault2kube.sorah.jp".to_string(),
+        );
+        labels.insert(
+            "vault2kube.sorah.jp/rule".to_string(),
+            rule.metadata.name.clone().unwrap_or_default(),
+        );
+        let mut annotations: BTreeMap<String, String> = BTreeMap::new();
+        for (name, tmpl) in rule.spec.destination_annotations.iter().flatten() {
+            annotations.insert(name.to_owned(), hb.render_template(tmpl, data)?);
+        }
+
+        let mut patch = serde_json::json!({
             "apiVersion": "v1",
             "kind": "Secret",
+            "type": secret_type,
             "metadata": {
                 "name": &rule.spec.destination_name,
                 "namespace": &namespace,
-                "labels": {
-                    "kubernetes.io/managed-by": "vault2kube.sorah.jp",
-                    "vault2kube.sorah.jp/rule": &rule.metadata.name
-                },
+                "labels": labels,
+                "annotations": annotations,
             },
             "stringData": string_data,
         });
+        // Omitting this releases an owner reference set previously, as we own the field through server-side apply
+        if rule.spec.owner_reference.unwrap_or(true) {
+            if let Some(uid) = &rule.metadata.uid {
+                patch["metadata"]["ownerReferences"] = serde_json::json!([{
+                    "apiVersion": "vault2kube.sorah.jp/v1",
+                    "kind": "VaultStoreRule",
+                    "name": &rule.metadata.name,
+                    "uid": uid,
+                    "controller": true,
+                }]);
+            }
+        }
+
+        self.recreate_secret_if_type_changed(rule, secret_type)
+            .await?;
 
         // Intentionally force
         // https://v1-17.docs.kubernetes.io/docs/reference/using-api/api-concepts/#conflicts
         // https://github.com/cybozu-go/cke/issues/311
-        let patch_response = secrets
-            .patch(
-                &rule.spec.destination_name,
-                &kube::api::PatchParams::apply("vault2kube.sorah.jp").force(),
-                serde_yaml::to_vec(&patch)?,
+        let params = kube::api::PatchParams::apply("vault2kube.sorah.jp").force();
+        let patch_yaml = serde_yaml::to_vec(&patch)?;
+        let patch_response = self
+            .kube_backoff
+            .retry(
+                || secrets.patch(&rule.spec.destination_name, &params, patch_yaml.clone()),
+                is_kube_error_retryable,
             )
             .await;
         // Retry as a create request when a resource does not exist yet
@@ -390,8 +1367,12 @@ impl Runner {
                     }
                     log::debug!("     (got 404, creating instead of patch)");
                     let patch_json: Secret = serde_json::from_value(patch)?;
-                    secrets
-                        .create(&kube::api::PostParams::default(), &patch_json)
+                    let params = kube::api::PostParams::default();
+                    self.kube_backoff
+                        .retry(
+                            || secrets.create(&params, &patch_json),
+                            is_kube_error_retryable,
+                        )
                         .await?;
                 }
                 _ => return Err(Box::new(e)),
@@ -400,27 +1381,83 @@ impl Runner {
         Ok(())
     }
 
+    // Type of a secret is immutable; delete an existing secret of a different type (e.g. Opaque secrets created by older
+    // versions), so it can be created again with the type. Secrets not managed by the rule are left as is.
+    async fn recreate_secret_if_type_changed(
+        &self,
+        rule: &VaultStoreRule,
+        secret_type: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secret = match self.get_secret(rule).await? {
+            Some(secret) => secret,
+            None => return Ok(()),
+        };
+        let current_type = secret.type_.as_deref().unwrap_or("Opaque");
+        if current_type == secret_type {
+            return Ok(());
+        }
+        let labels = secret.metadata.labels.unwrap_or_default();
+        if labels.get("vault2kube.sorah.jp/rule") != rule.metadata.name.as_ref() {
+            return Err(format!(
+                "secret {} has type {} and is not managed by this rule; delete it to change the type to {}",
+                &rule.spec.destination_name, current_type, secret_type
+            )
+            .into());
+        }
+        log::warn!(
+            "   * Secret type is changing from {} to {}; re-creating",
+            current_type,
+            secret_type
+        );
+        self.delete_secret(rule).await
+    }
+
+    async fn get_secret(
+        &self,
+        rule: &VaultStoreRule,
+    ) -> Result<Option<Secret>, Box<dyn std::error::Error>> {
+        let secrets: kube::Api<Secret> = kube::Api::namespaced(
+            self.kube.clone(),
+            rule.metadata
+                .namespace
+                .as_ref()
+                .unwrap_or(&"default".to_string()),
+        );
+        match self
+            .kube_backoff
+            .retry(
+                || secrets.get(&rule.spec.destination_name),
+                is_kube_error_retryable,
+            )
+            .await
+        {
+            Ok(secret) => Ok(Some(secret)),
+            Err(kube::Error::Api(ae)) if ae.code == 404 => Ok(None),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
     async fn rollout(
         &self,
-        namespace: &String,
-        resources: &Vec<VaultStoreRuleRollout>,
+        namespace: &str,
+        resources: &[VaultStoreRuleRollout],
     ) -> Result<(), Box<dyn std::error::Error>> {
         log::info!("===> Rolling out");
-        let mut iter = resources.iter();
-        while let Some(rollout) = iter.next() {
+        let iter = resources.iter();
+        for rollout in iter {
             let kind = rollout.kind.as_str();
             log::info!("   * restart: {}/{}/{}", namespace, &kind, &rollout.name);
             match kind {
                 "Deployment" => {
-                    self.rollout_single::<Deployment>(namespace, &kind, &rollout.name)
+                    self.rollout_single::<Deployment>(namespace, kind, &rollout.name)
                         .await
                 }
                 "DaemonSet" => {
-                    self.rollout_single::<DaemonSet>(namespace, &kind, &rollout.name)
+                    self.rollout_single::<DaemonSet>(namespace, kind, &rollout.name)
                         .await
                 }
                 "StatefulSet" => {
-                    self.rollout_single::<StatefulSet>(namespace, &kind, &rollout.name)
+                    self.rollout_single::<StatefulSet>(namespace, kind, &rollout.name)
                         .await
                 }
                 _ => return Err(Box::new(UnsupportedRolloutKind)),
@@ -433,7 +1470,7 @@ impl Runner {
         T: k8s_openapi::Resource + Clone + serde::de::DeserializeOwned + kube::api::Meta,
     >(
         &self,
-        namespace: &String,
+        namespace: &str,
         kind: &str,
         name: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
@@ -455,17 +1492,178 @@ impl Runner {
         // Intentionally force
         // https://v1-17.docs.kubernetes.io/docs/reference/using-api/api-concepts/#conflicts
         // https://github.com/cybozu-go/cke/issues/311
-        client
-            .patch(
-                name,
-                &kube::api::PatchParams::apply("vault2kube.sorah.jp").force(),
-                patch,
+        let params = kube::api::PatchParams::apply("vault2kube.sorah.jp").force();
+        self.kube_backoff
+            .retry(
+                || client.patch(name, &params, patch.clone()),
+                is_kube_error_retryable,
             )
             .await?;
         Ok(())
     }
 }
 
+// Server-side apply patches are idempotent, and create fails on conflicts; both are safe to retry
+fn is_kube_error_retryable(e: &kube::Error) -> bool {
+    match e {
+        kube::Error::Api(ae) => ae.code == 429 || ae.code >= 500,
+        kube::Error::ReqwestError(_) | kube::Error::HttpError(_) | kube::Error::RequestSend => true,
+        _ => false,
+    }
+}
+
+pub fn log_rule_failure(e: &(dyn std::error::Error + 'static)) {
+    log::error!("!!!! Failed: {}", e);
+    match e.downcast_ref::<VaultError>() {
+        Some(VaultError::PermissionDenied(_)) => {
+            log::error!("     (check Vault policies attached to the token of vault2kube)")
+        }
+        Some(ve) if ve.is_transient() => {
+            log::error!("     (Vault seems to be temporarily unavailable; will retry later)")
+        }
+        _ => {}
+    }
+}
+
+// Templates are rendered against secret data at the top level for compatibility. Metadata is available under `lease`,
+// `rule` and `data` (the secret data itself) unless the secret data has the same keys. Secrets are not rendered again on
+// renewals, so `lease` is as of acquisition.
+fn template_context(
+    rule: &VaultStoreRule,
+    data: &HashMap<String, serde_json::Value>,
+    lease: Option<serde_json::Value>,
+) -> HashMap<String, serde_json::Value> {
+    let mut context = data.clone();
+    let mut metadata = vec![
+        ("data", serde_json::json!(data)),
+        (
+            "rule",
+            serde_json::json!({
+                "name": &rule.metadata.name,
+                "namespace": rule.metadata.namespace.as_ref().unwrap_or(&"default".to_string()),
+                "source_path": &rule.spec.source_path,
+            }),
+        ),
+    ];
+    if let Some(lease) = lease {
+        metadata.push(("lease", lease));
+    }
+    for (key, value) in metadata {
+        context.entry(key.to_string()).or_insert(value);
+    }
+    context
+}
+
+fn set_lease_from_lookup(
+    status: &mut VaultStoreRuleStatus,
+    data: vault_client::LookupResponseData,
+) {
+    status.expires_at = data.expire_time;
+    status.renewable = Some(data.renewable);
+    // ttl in status is a duration granted on the last renewal, rather than the remaining ttl
+    if let Some(expire_time) = data.expire_time {
+        let granted_at = data.last_renewal.unwrap_or(data.issue_time);
+        status.ttl = Some((expire_time - granted_at).num_seconds().max(0) as u32);
+    }
+}
+
+// Keys required by each Secret type; at least one key in each group must be present
+const DESTINATION_TYPES: &[(&str, &[&[&str]])] = &[
+    ("Opaque", &[]),
+    ("kubernetes.io/tls", &[&["tls.crt"], &["tls.key"]]),
+    ("kubernetes.io/dockerconfigjson", &[&[".dockerconfigjson"]]),
+    ("kubernetes.io/dockercfg", &[&[".dockercfg"]]),
+    ("kubernetes.io/basic-auth", &[&["username", "password"]]),
+    ("kubernetes.io/ssh-auth", &[&["ssh-privatekey"]]),
+];
+
+/// Checks that a Secret of the given type can be created with the given keys.
+pub fn check_destination_type(secret_type: &str, keys: &[&str]) -> Result<(), crate::error::Error> {
+    let requirements = DESTINATION_TYPES
+        .iter()
+        .find(|(t, _)| *t == secret_type)
+        .map(|(_, requirements)| requirements)
+        .ok_or_else(|| UnsupportedDestinationType(secret_type.to_string()))?;
+    for group in requirements.iter() {
+        if !group.iter().any(|key| keys.contains(key)) {
+            return Err(MissingSecretKey(
+                secret_type.to_string(),
+                group.join(" or "),
+            ));
+        }
+    }
+    Ok(())
+}
+
+const FAILURE_BACKOFF_BASE_SECONDS: u64 = 60;
+const FAILURE_BACKOFF_MAX_SECONDS: u64 = 3600;
+
+/// Backoff to apply on rules failing repeatedly, based on status.consecutiveFailures.
+pub fn failure_backoff() -> Backoff {
+    Backoff {
+        max_retries: 0,
+        base_delay: std::time::Duration::from_secs(FAILURE_BACKOFF_BASE_SECONDS),
+        max_delay: std::time::Duration::from_secs(FAILURE_BACKOFF_MAX_SECONDS),
+    }
+}
+
+// Returns the time until a failing rule should not be retried. Changes to a rule or requests through annotations
+// are retried immediately, as they may fix the failure.
+fn backoff_until(rule: &VaultStoreRule) -> Option<DateTime<Utc>> {
+    let status = rule.status.as_ref()?;
+    let failures = status.consecutive_failures.unwrap_or(0);
+    let last_failed_at = status.last_failed_at?;
+    if failures == 0 || status.observed_generation != rule.metadata.generation {
+        return None;
+    }
+    let requested = ["renewRequestedAt", "rotateRequestedAt"]
+        .iter()
+        .any(|name| {
+            check_request_annotation(
+                Some(&last_failed_at),
+                rule.metadata
+                    .annotations
+                    .as_ref()
+                    .and_then(|a| a.get(&format!("vault2kube.sorah.jp/{}", name))),
+            )
+        });
+    if requested {
+        return None;
+    }
+    // Take the lower bound of jitter, so a controller scheduling with jitter is never skipped
+    let delay = chrono::Duration::from_std(failure_backoff().ceiling(failures - 1) / 2).ok()?;
+    let until = last_failed_at + delay;
+    if Utc::now() < until {
+        Some(until)
+    } else {
+        None
+    }
+}
+
+// Updates a condition, keeping lastTransitionTime unless its status changes
+fn set_condition(
+    conditions: &mut Vec<VaultStoreRuleCondition>,
+    type_: &str,
+    status: &str,
+    reason: &str,
+    message: Option<String>,
+    now: DateTime<Utc>,
+) {
+    let last_transition_time = conditions
+        .iter()
+        .find(|c| c.type_ == type_ && c.status == status)
+        .and_then(|c| c.last_transition_time)
+        .unwrap_or(now);
+    conditions.retain(|c| c.type_ != type_);
+    conditions.push(VaultStoreRuleCondition {
+        type_: type_.to_string(),
+        status: status.to_string(),
+        reason: Some(reason.to_string()),
+        message,
+        last_transition_time: Some(last_transition_time),
+    });
+}
+
 fn check_request_annotation(now: Option<&DateTime<Utc>>, iso8601str: Option<&String>) -> bool {
     if iso8601str.is_none() {
         return false;
@@ -473,7 +1671,7 @@ fn check_request_annotation(now: Option<&DateTime<Utc>>, iso8601str: Option<&Str
     if now.is_none() {
         return true;
     }
-    if let Ok(requested_at) = DateTime::parse_from_rfc3339(&iso8601str.unwrap()) {
+    if let Ok(requested_at) = DateTime::parse_from_rfc3339(iso8601str.unwrap()) {
         return now.unwrap() < &requested_at;
     }
     false
@@ -484,10 +1682,248 @@ fn is_time_after_deadline(
     target: &Option<DateTime<Utc>>,
     deadline_sec: Option<i32>,
 ) -> bool {
-    if target.is_none() || deadline_sec.is_none() {
-        return false;
+    deadline_of(target, deadline_sec)
+        .map(|deadline| &deadline <= now)
+        .unwrap_or(false)
+}
+
+fn deadline_of(target: &Option<DateTime<Utc>>, deadline_sec: Option<i32>) -> Option<DateTime<Utc>> {
+    let offset = chrono::Duration::seconds(deadline_sec? as i64);
+    Some(target.as_ref()?.to_owned() - offset)
+}
+
+/// Returns the earliest time when a rule needs to run again to renew, rotate or revoke its leases, given a status
+/// returned from the last run.
+pub fn next_deadline(
+    rule: &VaultStoreRule,
+    status: &VaultStoreRuleStatus,
+) -> Option<DateTime<Utc>> {
+    match rule.spec.source_kind.as_deref() {
+        // expiresAt holds the next password rotation by Vault
+        Some("database-static") => return status.expires_at,
+        Some("kv-v2") => return None,
+        _ => {}
+    }
+    // No lease to keep fresh; acquire one as soon as possible
+    if status.expires_at.is_none() {
+        return Some(Utc::now());
+    }
+    let mut deadlines = vec![
+        deadline_of(&status.expires_at, Some(0)),
+        deadline_of(&status.expires_at, rule.spec.renew_before_seconds),
+        deadline_of(&status.expires_at, rule.spec.rotate_before_seconds),
+    ];
+    if status.last_lease_id.is_some() {
+        deadlines.push(deadline_of(
+            &status.rotated_at,
+            rule.spec.revoke_after_seconds.map(|s| -s),
+        ));
+    }
+    deadlines.into_iter().flatten().min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crd::VaultStoreRuleSpec;
+
+    fn rule(source_kind: Option<&str>) -> VaultStoreRule {
+        VaultStoreRule::new(
+            "app",
+            VaultStoreRuleSpec {
+                source_path: "database/creds/app".to_string(),
+                source_kind: source_kind.map(|k| k.to_string()),
+                destination_name: "app".to_string(),
+                renew_before_seconds: Some(600),
+                rotate_before_seconds: Some(300),
+                revoke_after_seconds: Some(60),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_next_deadline_lease() {
+        let status = VaultStoreRuleStatus {
+            lease_id: Some("lease".to_string()),
+            expires_at: Some(at("2020-01-01T01:00:00Z")),
+            ..Default::default()
+        };
+        // renewBeforeSeconds is the earliest
+        assert_eq!(
+            next_deadline(&rule(None), &status),
+            Some(at("2020-01-01T00:50:00Z"))
+        );
+
+        let mut rotate_only = rule(Some("lease"));
+        rotate_only.spec.renew_before_seconds = None;
+        assert_eq!(
+            next_deadline(&rotate_only, &status),
+            Some(at("2020-01-01T00:55:00Z"))
+        );
+
+        // Without renew and rotate windows, a rule is due at the expiry
+        let mut no_window = rule(None);
+        no_window.spec.renew_before_seconds = None;
+        no_window.spec.rotate_before_seconds = None;
+        assert_eq!(
+            next_deadline(&no_window, &status),
+            Some(at("2020-01-01T01:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_next_deadline_revoke() {
+        let status = VaultStoreRuleStatus {
+            lease_id: Some("lease".to_string()),
+            last_lease_id: Some("last-lease".to_string()),
+            rotated_at: Some(at("2020-01-01T00:00:00Z")),
+            expires_at: Some(at("2020-01-01T01:00:00Z")),
+            ..Default::default()
+        };
+        assert_eq!(
+            next_deadline(&rule(None), &status),
+            Some(at("2020-01-01T00:01:00Z"))
+        );
+
+        // revokeAfterSeconds is ignored without a lease to revoke
+        let status = VaultStoreRuleStatus {
+            last_lease_id: None,
+            ..status
+        };
+        assert_eq!(
+            next_deadline(&rule(None), &status),
+            Some(at("2020-01-01T00:50:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_next_deadline_capped_renewal() {
+        // A capped lease is rotated in the same run and kept as the last lease to revoke
+        let status = VaultStoreRuleStatus {
+            lease_id: Some("new-lease".to_string()),
+            last_lease_id: Some("capped-lease".to_string()),
+            rotated_at: Some(at("2020-01-01T00:00:00Z")),
+            expires_at: Some(at("2020-01-01T00:30:00Z")),
+            ..Default::default()
+        };
+        assert_eq!(
+            next_deadline(&rule(None), &status),
+            Some(at("2020-01-01T00:01:00Z"))
+        );
+
+        // A lease rule without expiry has no lease to keep; run again as soon as possible
+        let status = VaultStoreRuleStatus::default();
+        let before = Utc::now();
+        let deadline = next_deadline(&rule(None), &status).unwrap();
+        assert!(before <= deadline && deadline <= Utc::now());
+    }
+
+    #[test]
+    fn test_next_deadline_static() {
+        let status = VaultStoreRuleStatus {
+            expires_at: Some(at("2020-01-01T01:00:00Z")),
+            source_version: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(next_deadline(&rule(Some("kv-v2")), &status), None);
+        assert_eq!(
+            next_deadline(&rule(Some("database-static")), &status),
+            Some(at("2020-01-01T01:00:00Z"))
+        );
+        assert_eq!(
+            next_deadline(
+                &rule(Some("database-static")),
+                &VaultStoreRuleStatus::default()
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_template_context() {
+        let data: HashMap<String, serde_json::Value> = vec![
+            ("username".to_string(), serde_json::json!("user")),
+            ("password".to_string(), serde_json::json!("pass")),
+        ]
+        .into_iter()
+        .collect();
+        let context = template_context(
+            &rule(None),
+            &data,
+            Some(serde_json::json!({"lease_id": "database/creds/app/abc"})),
+        );
+        assert_eq!(context["username"], serde_json::json!("user"));
+        assert_eq!(context["data"]["password"], serde_json::json!("pass"));
+        assert_eq!(
+            context["lease"]["lease_id"],
+            serde_json::json!("database/creds/app/abc")
+        );
+        assert_eq!(context["rule"]["name"], serde_json::json!("app"));
+        assert_eq!(context["rule"]["namespace"], serde_json::json!("default"));
+        assert_eq!(
+            context["rule"]["source_path"],
+            serde_json::json!("database/creds/app")
+        );
+
+        let hb = handlebars::Handlebars::new();
+        assert_eq!(
+            hb.render_template("{{username}}@{{rule.name}}:{{lease.lease_id}}", &context)
+                .unwrap(),
+            "user@app:database/creds/app/abc"
+        );
+
+        // No lease metadata for rules without leases
+        assert!(!template_context(&rule(None), &data, None).contains_key("lease"));
+    }
+
+    #[test]
+    fn test_template_context_data_precedence() {
+        let data: HashMap<String, serde_json::Value> = vec![
+            ("lease".to_string(), serde_json::json!("data-lease")),
+            ("rule".to_string(), serde_json::json!("data-rule")),
+            ("data".to_string(), serde_json::json!("data-data")),
+        ]
+        .into_iter()
+        .collect();
+        let context = template_context(
+            &rule(None),
+            &data,
+            Some(serde_json::json!({"lease_id": "database/creds/app/abc"})),
+        );
+        assert_eq!(context["lease"], serde_json::json!("data-lease"));
+        assert_eq!(context["rule"], serde_json::json!("data-rule"));
+        assert_eq!(context["data"], serde_json::json!("data-data"));
+    }
+
+    #[test]
+    fn test_check_destination_type() {
+        assert!(check_destination_type("Opaque", &[]).is_ok());
+        assert!(check_destination_type("kubernetes.io/tls", &["tls.crt", "tls.key"]).is_ok());
+        assert!(check_destination_type("kubernetes.io/basic-auth", &["username"]).is_ok());
+        assert!(check_destination_type("kubernetes.io/basic-auth", &["password"]).is_ok());
+        assert!(
+            check_destination_type("kubernetes.io/ssh-auth", &["ssh-privatekey", "extra"]).is_ok()
+        );
+
+        match check_destination_type("kubernetes.io/tls", &["tls.crt"]) {
+            Err(MissingSecretKey(t, key)) => {
+                assert_eq!(t, "kubernetes.io/tls");
+                assert_eq!(key, "tls.key");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match check_destination_type("kubernetes.io/basic-auth", &["token"]) {
+            Err(MissingSecretKey(_, key)) => assert_eq!(key, "username or password"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(matches!(
+            check_destination_type("kubernetes.io/service-account-token", &["token"]),
+            Err(UnsupportedDestinationType(_))
+        ));
     }
-    let offset = chrono::Duration::seconds(deadline_sec.unwrap() as i64);
-    let deadline = target.unwrap() - offset;
-    &deadline <= now
 }
@@ -1,17 +1,46 @@
 use anyhow::anyhow;
+use chrono::{DateTime, Utc};
 use log;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::env;
-use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use crate::error::VaultError;
+use crate::retry::Backoff;
 
 const K8S_INCLUSTER_SERVICE_TOKENFILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+const TOKEN_RENEWAL_CHECK_INTERVAL_SECONDS: u64 = 30;
+const DEFAULT_MAX_RETRIES: u32 = 2;
+// Vault responds quickly unless something is wrong; never let a hung connection stall the controller
+const REQUEST_TIMEOUT_SECONDS: u64 = 30;
 
 #[derive(Debug, Clone)]
 pub struct Client {
     addr: reqwest::Url,
-    token: Option<String>,
+    // Shared among clones, so a token issued by login again is used by all of them
+    token: Arc<Mutex<Option<String>>>,
+    // Present only when a token is issued by login
+    token_lease: Arc<Mutex<Option<TokenLease>>>,
     http: reqwest::Client,
+    backoff: Backoff,
+}
+
+#[derive(Debug, Clone)]
+struct TokenLease {
+    lease_duration: u32,
+    renewable: bool,
+    expires_at: DateTime<Utc>,
+}
+
+impl TokenLease {
+    fn from_auth(auth: &AuthResponseData) -> Self {
+        TokenLease {
+            lease_duration: auth.lease_duration,
+            renewable: auth.renewable,
+            expires_at: Utc::now() + chrono::Duration::seconds(auth.lease_duration as i64),
+        }
+    }
 }
 
 impl Client {
@@ -28,6 +57,15 @@ impl Client {
             }
             Err(_e) => None,
         };
+        let identity = match (env::var("VAULT_CLIENT_CERT"), env::var("VAULT_CLIENT_KEY")) {
+            (Ok(cert_path), Ok(key_path)) => Some(load_client_identity(&cert_path, &key_path)?),
+            (Err(_e), Err(_e2)) => None,
+            _ => {
+                return Err(anyhow!(
+                    "VAULT_CLIENT_CERT and VAULT_CLIENT_KEY must be present together"
+                ))
+            }
+        };
         let token = env::var("VAULT_TOKEN").ok();
         let token_given = token.is_some();
 
@@ -43,34 +81,30 @@ impl Client {
                 reqwest::header::HeaderValue::from_str(&ns)?,
             );
         }
-        http = http.default_headers(http_headers);
+        http = http
+            .default_headers(http_headers)
+            .timeout(std::time::Duration::from_secs(REQUEST_TIMEOUT_SECONDS));
         if let Some(c) = cacert {
             http = http.add_root_certificate(c);
         }
+        if let Some(i) = identity {
+            http = http.identity(i);
+        }
 
-        let mut client = Client {
+        let client = Client {
             addr,
-            token,
+            token: Arc::new(Mutex::new(token)),
+            token_lease: Arc::new(Mutex::new(None)),
             http: http.build()?,
+            backoff: Backoff::from_env("VAULT_MAX_RETRIES", DEFAULT_MAX_RETRIES)?,
         };
         if !token_given {
-            let k8s_jwt_path = env::var("VAULT_K8S_TOKEN_PATH")
-                .unwrap_or(K8S_INCLUSTER_SERVICE_TOKENFILE.to_string());
-            let k8s_jwt = std::fs::read(k8s_jwt_path)?;
-            client.authenticate_using_kubernetes(
-			   env::var("VAULT_K8S_PATH").ok().ok_or(
-				   anyhow!("VAULT_K8S_PATH, VAULT_K8S_ROLE must be present when no VAULT_TOKEN is present"),
-			   )?,
-			   env::var("VAULT_K8S_ROLE").ok().ok_or(
-				   anyhow!("VAULT_K8S_PATH, VAULT_K8S_ROLE must be present when no VAULT_TOKEN is present"),
-			   )?,
-			   String::from_utf8(k8s_jwt)?,
-			).await?;
+            client.authenticate_from_env().await?;
         }
         Ok(client)
     }
 
-    pub async fn read(&self, path: &str) -> Result<LeaseResponse, Box<dyn Error>> {
+    pub async fn read(&self, path: &str) -> Result<LeaseResponse, VaultError> {
         let response: LeaseResponse = self.get(path).await?.json().await?;
         Ok(response)
     }
@@ -79,33 +113,227 @@ impl Client {
         &self,
         path: &str,
         payload: &serde_json::Map<String, serde_json::Value>,
-    ) -> Result<LeaseResponse, Box<dyn Error>> {
+    ) -> Result<LeaseResponse, VaultError> {
         let response: LeaseResponse = self.post(path, &payload).await?.json().await?;
         Ok(response)
     }
 
-    pub async fn renew(&self, lease_id: &str) -> Result<RenewResponse, Box<dyn Error>> {
+    pub async fn renew(&self, lease_id: &str) -> Result<RenewResponse, VaultError> {
         let payload = RenewRequest {
             lease_id: lease_id.to_string(),
         };
         let response: RenewResponse = self
-            .post("sys/leases/renew", &payload)
+            .post_idempotent("sys/leases/renew", &payload)
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
+    pub async fn lookup(&self, lease_id: &str) -> Result<LookupResponse, VaultError> {
+        let payload = RenewRequest {
+            lease_id: lease_id.to_string(),
+        };
+        let response: LookupResponse = self
+            .post_idempotent("sys/leases/lookup", &payload)
             .await?
             .json()
             .await?;
         Ok(response)
     }
 
-    pub async fn revoke(&self, lease_id: &str) -> Result<reqwest::Response, Box<dyn Error>> {
+    pub async fn revoke(&self, lease_id: &str) -> Result<reqwest::Response, VaultError> {
         let payload = RenewRequest {
             lease_id: lease_id.to_string(),
         };
-        let response = self.post("sys/leases/revoke", &payload).await?;
+        let response = self.post_idempotent("sys/leases/revoke", &payload).await?;
         Ok(response)
     }
 
+    /// Renews the token when it passes 2/3 of its TTL, or logs in again when it can't be renewed anymore (not renewable,
+    /// or capped to max_ttl). Does nothing for a token not issued by login (VAULT_TOKEN).
+    pub async fn renew_token_if_needed(&self) -> anyhow::Result<()> {
+        let token_lease = self.token_lease.lock().unwrap().clone();
+        let token_lease = match token_lease {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        // lease_duration is 0 for non-expiring tokens
+        if token_lease.lease_duration == 0 {
+            return Ok(());
+        }
+        let renew_at = token_lease.expires_at
+            - chrono::Duration::seconds(token_lease.lease_duration as i64 / 3);
+        if Utc::now() < renew_at {
+            return Ok(());
+        }
+        if !token_lease.renewable {
+            log::info!(
+                "Vault token can't be renewed anymore and expires at {:?}; logging in again",
+                token_lease.expires_at
+            );
+            return self.authenticate_from_env().await;
+        }
+
+        log::info!("Renewing Vault token");
+        let response: AuthResponse = match self
+            .post_idempotent("auth/token/renew-self", &serde_json::json!({}))
+            .await
+        {
+            Ok(response) => response.json().await?,
+            Err(e) => {
+                log::warn!("   ! Failed to renew Vault token ({}); logging in again", e);
+                return self.authenticate_from_env().await;
+            }
+        };
+        let mut renewed = TokenLease::from_auth(&response.auth);
+        if response.auth.lease_duration < token_lease.lease_duration {
+            log::warn!(
+                "   * Vault token TTL seems to be capped to max_ttl (ttl={:?}); will log in again",
+                response.auth.lease_duration
+            );
+            // Further renewals never extend it; log in again at the next renewal time instead
+            renewed.renewable = false;
+        }
+        *self.token_lease.lock().unwrap() = Some(renewed);
+        Ok(())
+    }
+
+    /// Keeps the token renewed in background for long-running processes. Never returns.
+    pub async fn keep_token_renewed(self) {
+        loop {
+            if let Err(e) = self.renew_token_if_needed().await {
+                log::error!("!!!! Failed to renew Vault token: {}", e);
+            }
+            tokio::time::delay_for(std::time::Duration::from_secs(
+                TOKEN_RENEWAL_CHECK_INTERVAL_SECONDS,
+            ))
+            .await;
+        }
+    }
+
+    /// Revokes the token when it is issued by login, to avoid leaving it until its expiry. Note that Vault revokes all
+    /// leases acquired with the token as well.
+    pub async fn revoke_token(&self) -> anyhow::Result<()> {
+        if self.token_lease.lock().unwrap().is_none() {
+            return Ok(());
+        }
+        log::info!("Revoking Vault token");
+        self.post_idempotent("auth/token/revoke-self", &serde_json::json!({}))
+            .await?;
+        *self.token_lease.lock().unwrap() = None;
+        Ok(())
+    }
+
+    // Logs in with a request not carrying the current token, as it may be expired already
+    async fn login<T: Serialize + ?Sized>(&self, path: &str, payload: &T) -> anyhow::Result<()> {
+        let url = self.addr.join(path)?;
+        let req = self.http.post(url).json(&payload).build()?;
+        let response: AuthResponse = self
+            .backoff
+            .retry(
+                || self.execute(req.try_clone().unwrap()),
+                |e: &VaultError| e.is_retryable(false),
+            )
+            .await?
+            .json()
+            .await?;
+        *self.token_lease.lock().unwrap() = Some(TokenLease::from_auth(&response.auth));
+        *self.token.lock().unwrap() = Some(response.auth.client_token);
+        Ok(())
+    }
+
+    // Authenticate using one of auth methods configured by environment variables. AppRole, JWT, then TLS certificate
+    // is preferred when configured, then falls back to Kubernetes. Files are read on each login, as they may be rotated
+    // (e.g. projected service account tokens).
+    async fn authenticate_from_env(&self) -> anyhow::Result<()> {
+        if let Ok(approle_path) = env::var("VAULT_APPROLE_PATH") {
+            let role_id = env::var("VAULT_ROLE_ID").ok().ok_or(anyhow!(
+                "VAULT_ROLE_ID must be present when VAULT_APPROLE_PATH is present"
+            ))?;
+            let secret_id = match env::var("VAULT_SECRET_ID") {
+                Ok(secret_id) => Some(secret_id),
+                Err(_e) => match env::var("VAULT_SECRET_ID_FILE") {
+                    Ok(path) => Some(std::fs::read_to_string(path)?.trim_end().to_string()),
+                    Err(_e) => None,
+                },
+            };
+            return self
+                .authenticate_using_approle(approle_path, role_id, secret_id)
+                .await;
+        }
+
+        if let Ok(jwt_path) = env::var("VAULT_JWT_PATH") {
+            let role = env::var("VAULT_JWT_ROLE").ok().ok_or(anyhow!(
+                "VAULT_JWT_ROLE must be present when VAULT_JWT_PATH is present"
+            ))?;
+            let jwt_token_path = env::var("VAULT_JWT_TOKEN_PATH")
+                .unwrap_or(K8S_INCLUSTER_SERVICE_TOKENFILE.to_string());
+            let jwt = std::fs::read_to_string(jwt_token_path)?;
+            return self
+                .authenticate_using_jwt(jwt_path, role, jwt.trim_end().to_string())
+                .await;
+        }
+
+        if let Ok(cert_path) = env::var("VAULT_CERT_PATH") {
+            return self
+                .authenticate_using_cert(cert_path, env::var("VAULT_CERT_ROLE").ok())
+                .await;
+        }
+
+        let k8s_jwt_path =
+            env::var("VAULT_K8S_TOKEN_PATH").unwrap_or(K8S_INCLUSTER_SERVICE_TOKENFILE.to_string());
+        let k8s_jwt = std::fs::read(k8s_jwt_path)?;
+        self.authenticate_using_kubernetes(
+            env::var("VAULT_K8S_PATH").ok().ok_or(anyhow!(
+                "VAULT_K8S_PATH, VAULT_K8S_ROLE must be present when no VAULT_TOKEN is present"
+            ))?,
+            env::var("VAULT_K8S_ROLE").ok().ok_or(anyhow!(
+                "VAULT_K8S_PATH, VAULT_K8S_ROLE must be present when no VAULT_TOKEN is present"
+            ))?,
+            String::from_utf8(k8s_jwt)?,
+        )
+        .await
+    }
+
+    async fn authenticate_using_approle(
+        &self,
+        path: String,
+        role_id: String,
+        secret_id: Option<String>,
+    ) -> anyhow::Result<()> {
+        log::info!("Authenticating with AppRole auth backend");
+        let login_path = format!("{}/login", path);
+        let payload = AppRoleAuthRequest { role_id, secret_id };
+        self.login(&login_path, &payload).await
+    }
+
+    async fn authenticate_using_jwt(
+        &self,
+        path: String,
+        role: String,
+        jwt: String,
+    ) -> anyhow::Result<()> {
+        log::info!("Authenticating with JWT auth backend");
+        let login_path = format!("{}/login", path);
+        let payload = JwtAuthRequest { role, jwt };
+        self.login(&login_path, &payload).await
+    }
+
+    // Client certificate is presented by the underlying HTTP client (VAULT_CLIENT_CERT, VAULT_CLIENT_KEY)
+    async fn authenticate_using_cert(
+        &self,
+        path: String,
+        name: Option<String>,
+    ) -> anyhow::Result<()> {
+        log::info!("Authenticating with TLS certificate auth backend");
+        let login_path = format!("{}/login", path);
+        let payload = CertAuthRequest { name };
+        self.login(&login_path, &payload).await
+    }
+
     async fn authenticate_using_kubernetes(
-        &mut self,
+        &self,
         path: String,
         role: String,
         jwt: String,
@@ -113,45 +341,119 @@ impl Client {
         log::info!("Authenticating with Kubernetes auth backend");
         let login_path = format!("{}/login", path);
         let payload = KubernetesAuthRequest { role, jwt };
-        let response: AuthResponse = self.post(&login_path, &payload).await?.json().await?;
-        self.token = Some(response.auth.client_token);
-        Ok(())
+        self.login(&login_path, &payload).await
     }
 
-    async fn get(&self, path: &str) -> Result<reqwest::Response, reqwest::Error> {
-        let url = self.addr.join(&path).unwrap();
+    // Requests other than *_idempotent are considered to have side effects (e.g. reading database/creds mints a lease)
+    async fn get(&self, path: &str) -> Result<reqwest::Response, VaultError> {
+        let url = self.addr.join(path).unwrap();
         let request = self.http.get(url);
-        self.send(request).await
+        self.send(request, false).await
     }
 
     async fn post<T: Serialize + ?Sized>(
         &self,
         path: &str,
         payload: &T,
-    ) -> Result<reqwest::Response, reqwest::Error> {
-        let url = self.addr.join(&path).unwrap();
+    ) -> Result<reqwest::Response, VaultError> {
+        let url = self.addr.join(path).unwrap();
         let request = self.http.post(url).json(&payload);
-        self.send(request).await
+        self.send(request, false).await
+    }
+
+    async fn post_idempotent<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        payload: &T,
+    ) -> Result<reqwest::Response, VaultError> {
+        let url = self.addr.join(path).unwrap();
+        let request = self.http.post(url).json(&payload);
+        self.send(request, true).await
     }
 
     async fn send(
         &self,
         mut request: reqwest::RequestBuilder,
-    ) -> Result<reqwest::Response, reqwest::Error> {
-        if let Some(token) = &self.token {
-            request = request.header("X-Vault-Token", token.to_owned());
+        idempotent: bool,
+    ) -> Result<reqwest::Response, VaultError> {
+        if let Some(token) = self.token.lock().unwrap().clone() {
+            request = request.header("X-Vault-Token", token);
         }
         let req = request.build()?;
-        self.http.execute(req).await?.error_for_status()
+        self.backoff
+            .retry(
+                // Bodies are always in memory (JSON), so cloning never fails
+                || self.execute(req.try_clone().unwrap()),
+                |e: &VaultError| e.is_retryable(idempotent),
+            )
+            .await
+    }
+
+    async fn execute(&self, req: reqwest::Request) -> Result<reqwest::Response, VaultError> {
+        let response = self.http.execute(req).await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        // Vault explains errors in a body, which error_for_status() would throw away
+        let errors = response
+            .json::<ErrorResponse>()
+            .await
+            .map(|r| r.errors)
+            .unwrap_or_default();
+        Err(VaultError::from_response(status, errors))
     }
 }
 
+// reqwest with native-tls only accepts PKCS#12 for client identity, so convert PEM files to PKCS#12 here
+fn load_client_identity(cert_path: &str, key_path: &str) -> anyhow::Result<reqwest::Identity> {
+    let pkcs12 = pkcs12_from_pem_files(cert_path, key_path)?;
+    Ok(reqwest::Identity::from_pkcs12_der(&pkcs12, "")?)
+}
+
+// Converts a PEM certificate chain and a private key into PKCS#12 DER with an empty password
+pub(crate) fn pkcs12_from_pem_files(cert_path: &str, key_path: &str) -> anyhow::Result<Vec<u8>> {
+    let mut certs = openssl::x509::X509::stack_from_pem(&std::fs::read(cert_path)?)?;
+    if certs.is_empty() {
+        return Err(anyhow!("{} contains no certificate", cert_path));
+    }
+    let cert = certs.remove(0);
+    let key = openssl::pkey::PKey::private_key_from_pem(&std::fs::read(key_path)?)?;
+    let mut chain = openssl::stack::Stack::new()?;
+    for c in certs {
+        chain.push(c)?;
+    }
+    let mut builder = openssl::pkcs12::Pkcs12::builder();
+    builder.ca(chain);
+    let pkcs12 = builder.build("", "vault2kube", &key, &cert)?;
+    Ok(pkcs12.to_der()?)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct KubernetesAuthRequest {
     jwt: String,
     role: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct CertAuthRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JwtAuthRequest {
+    jwt: String,
+    role: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppRoleAuthRequest {
+    role_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_id: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct AuthResponse {
     auth: AuthResponseData,
@@ -164,7 +466,13 @@ struct AuthResponseData {
     pub policies: Vec<String>,
     pub lease_duration: u32,
     pub renewable: bool,
-    pub metadata: HashMap<String, String>,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ErrorResponse {
+    #[serde(default)]
+    errors: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -190,3 +498,20 @@ pub struct RenewResponse {
     pub renewable: bool,
     pub warnings: Option<Vec<String>>,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LookupResponse {
+    pub request_id: String,
+    pub data: LookupResponseData,
+    pub warnings: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LookupResponseData {
+    pub id: String,
+    pub issue_time: DateTime<Utc>,
+    pub expire_time: Option<DateTime<Utc>>,
+    pub last_renewal: Option<DateTime<Utc>>,
+    pub renewable: bool,
+    pub ttl: i64,
+}
@@ -0,0 +1,406 @@
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::convert::Infallible;
+
+use crate::crd::VaultStoreRule;
+
+const SUPPORTED_ROLLOUT_KINDS: &[&str] = &["Deployment", "DaemonSet", "StatefulSet"];
+
+/// Loads a TLS server identity from PEM files.
+pub fn load_identity(cert_path: &str, key_path: &str) -> anyhow::Result<native_tls::Identity> {
+    let pkcs12 = crate::vault_client::pkcs12_from_pem_files(cert_path, key_path)?;
+    Ok(native_tls::Identity::from_pkcs12(&pkcs12, "")?)
+}
+
+/// Serves ValidatingAdmissionWebhook at /validate and the conversion webhook at /convert for VaultStoreRule over HTTPS.
+/// Never returns unless failed.
+pub async fn serve(
+    addr: std::net::SocketAddr,
+    identity: native_tls::Identity,
+) -> anyhow::Result<()> {
+    let acceptor = tokio_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
+    let mut listener = tokio::net::TcpListener::bind(&addr).await?;
+    log::info!("Serving webhook at https://{}", addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("!!!! TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let service = service_fn(handle);
+            if let Err(e) = hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .await
+            {
+                log::warn!("!!!! Connection with {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::POST {
+        return Ok(respond(StatusCode::NOT_FOUND, Body::from("not found")));
+    }
+    let path = req.uri().path().to_string();
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return Ok(respond(StatusCode::BAD_REQUEST, Body::from(e.to_string()))),
+    };
+    let response = match path.as_str() {
+        "/validate" => serde_json::from_slice(&body)
+            .map(review_rule)
+            .and_then(|r| serde_json::to_vec(&r)),
+        "/convert" => serde_json::from_slice(&body)
+            .map(convert_rules)
+            .and_then(|r| serde_json::to_vec(&r)),
+        _ => return Ok(respond(StatusCode::NOT_FOUND, Body::from("not found"))),
+    };
+    let json = match response {
+        Ok(json) => json,
+        Err(e) => return Ok(respond(StatusCode::BAD_REQUEST, Body::from(e.to_string()))),
+    };
+    Ok(respond(StatusCode::OK, Body::from(json)))
+}
+
+fn convert_rules(review: ConversionReview) -> ConversionReview {
+    let request = review.request.unwrap_or_default();
+    let desired_api_version = request.desired_api_version;
+    let converted: anyhow::Result<Vec<serde_json::Value>> = request
+        .objects
+        .into_iter()
+        .map(|object| crate::crd::convert(object, &desired_api_version))
+        .collect();
+    let (converted_objects, result) = match converted {
+        Ok(objects) => (
+            objects,
+            ConversionResult {
+                status: "Success".to_string(),
+                message: None,
+            },
+        ),
+        Err(e) => {
+            log::warn!("!!!! Conversion failed: {}", e);
+            (
+                vec![],
+                ConversionResult {
+                    status: "Failure".to_string(),
+                    message: Some(e.to_string()),
+                },
+            )
+        }
+    };
+    ConversionReview {
+        api_version: review.api_version,
+        kind: review.kind,
+        request: None,
+        response: Some(ConversionResponse {
+            uid: request.uid,
+            converted_objects,
+            result,
+        }),
+    }
+}
+
+fn respond(status: StatusCode, body: Body) -> Response<Body> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
+
+fn review_rule(review: AdmissionReview) -> AdmissionReview {
+    let request = review.request.unwrap_or_default();
+    let errors = if needs_validation(&request) {
+        match request
+            .object
+            .clone()
+            .map(serde_json::from_value::<VaultStoreRule>)
+        {
+            Some(Ok(rule)) => validate(&rule),
+            Some(Err(e)) => vec![format!("invalid VaultStoreRule: {}", e)],
+            None => vec![],
+        }
+    } else {
+        vec![]
+    };
+    let status = if errors.is_empty() {
+        None
+    } else {
+        log::info!(
+            "Denied {}/{}: {}",
+            request.namespace.unwrap_or_default(),
+            request.name.unwrap_or_default(),
+            errors.join("; ")
+        );
+        Some(AdmissionStatus {
+            code: 422,
+            message: errors.join("; "),
+        })
+    };
+    AdmissionReview {
+        api_version: review.api_version,
+        kind: review.kind,
+        request: None,
+        response: Some(AdmissionResponse {
+            uid: request.uid,
+            allowed: status.is_none(),
+            status,
+        }),
+    }
+}
+
+// Only new rules and spec changes are validated. Rules created before the webhook (or before a new check) must remain
+// updatable, so vault2kube can update their metadata such as finalizers, and deletion never gets stuck.
+fn needs_validation(request: &AdmissionRequest) -> bool {
+    let object = match &request.object {
+        Some(object) => object,
+        // DELETE requests have no object
+        None => return false,
+    };
+    if !object["metadata"]["deletionTimestamp"].is_null() {
+        return false;
+    }
+    match &request.old_object {
+        Some(old_object) => old_object["spec"] != object["spec"],
+        None => true,
+    }
+}
+
+/// Returns problems in a rule which otherwise surface only when the rule is executed.
+pub fn validate(rule: &VaultStoreRule) -> Vec<String> {
+    let spec = &rule.spec;
+    let mut errors = Vec::new();
+
+    if !is_dns_subdomain(&spec.destination_name) {
+        errors.push(format!(
+            "destinationName {:?} must be a lowercase RFC 1123 subdomain",
+            &spec.destination_name
+        ));
+    }
+
+    let mut keys = HashSet::new();
+    for tmpl in spec.templates.iter() {
+        if !keys.insert(tmpl.key.as_str()) {
+            errors.push(format!("templates: duplicate key {:?}", &tmpl.key));
+        }
+        if !is_secret_key(&tmpl.key) {
+            errors.push(format!(
+                "templates: key {:?} must consist of alphanumeric characters, '-', '_' or '.'",
+                &tmpl.key
+            ));
+        }
+        if let Err(e) = handlebars::Template::compile(&tmpl.template) {
+            errors.push(format!(
+                "templates: key {:?} has invalid template: {}",
+                &tmpl.key, e
+            ));
+        }
+    }
+
+    for (field, templates) in [
+        ("destinationLabels", &spec.destination_labels),
+        ("destinationAnnotations", &spec.destination_annotations),
+    ]
+    .iter()
+    {
+        for (name, tmpl) in templates.iter().flatten() {
+            if let Err(e) = handlebars::Template::compile(tmpl) {
+                errors.push(format!("{}: {:?} has invalid template: {}", field, name, e));
+            }
+        }
+    }
+
+    let keys: Vec<&str> = spec.templates.iter().map(|t| t.key.as_str()).collect();
+    if let Err(e) = crate::runner::check_destination_type(
+        spec.destination_type.as_deref().unwrap_or("Opaque"),
+        &keys,
+    ) {
+        errors.push(format!("destinationType: {}", e));
+    }
+
+    for rollout in spec.rollout_restarts.iter().flatten() {
+        if !SUPPORTED_ROLLOUT_KINDS.contains(&rollout.kind.as_str()) {
+            errors.push(format!(
+                "rolloutRestarts: unsupported kind {:?} (supported: {})",
+                &rollout.kind,
+                SUPPORTED_ROLLOUT_KINDS.join(", ")
+            ));
+        }
+    }
+
+    let is_lease = spec.source_kind.as_deref().unwrap_or("lease") == "lease";
+    if is_lease && spec.renew_before_seconds.is_none() && spec.rotate_before_seconds.is_none() {
+        errors.push(
+            "at least either renewBeforeSeconds or rotateBeforeSeconds must be given".to_string(),
+        );
+    }
+
+    errors
+}
+
+fn is_dns_subdomain(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+        })
+}
+
+fn is_secret_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 253
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+// Minimal subset of admission.k8s.io AdmissionReview; both v1 and v1beta1 share these fields
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AdmissionReview {
+    api_version: String,
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<AdmissionRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<AdmissionResponse>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AdmissionRequest {
+    uid: String,
+    name: Option<String>,
+    namespace: Option<String>,
+    object: Option<serde_json::Value>,
+    old_object: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AdmissionResponse {
+    uid: String,
+    allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<AdmissionStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AdmissionStatus {
+    code: u16,
+    message: String,
+}
+
+// Minimal subset of apiextensions.k8s.io/v1 ConversionReview
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConversionReview {
+    api_version: String,
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<ConversionRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<ConversionResponse>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConversionRequest {
+    uid: String,
+    #[serde(rename = "desiredAPIVersion")]
+    desired_api_version: String,
+    objects: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConversionResponse {
+    uid: String,
+    converted_objects: Vec<serde_json::Value>,
+    result: ConversionResult,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ConversionResult {
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(
+        object: Option<serde_json::Value>,
+        old_object: Option<serde_json::Value>,
+    ) -> AdmissionRequest {
+        AdmissionRequest {
+            uid: "uid".to_string(),
+            name: Some("rule".to_string()),
+            namespace: Some("default".to_string()),
+            object,
+            old_object,
+        }
+    }
+
+    fn rule(destination_name: &str) -> serde_json::Value {
+        json!({
+            "apiVersion": "vault2kube.sorah.jp/v1",
+            "kind": "VaultStoreRule",
+            "metadata": { "name": "rule", "namespace": "default" },
+            "spec": {
+                "sourcePath": "database/creds/app",
+                "destinationName": destination_name,
+                "templates": [{ "key": "password", "template": "{{password}}" }],
+                "renewBeforeSeconds": 600,
+            },
+        })
+    }
+
+    #[test]
+    fn test_needs_validation() {
+        assert!(needs_validation(&request(Some(rule("app")), None)));
+        assert!(needs_validation(&request(
+            Some(rule("app")),
+            Some(rule("old"))
+        )));
+        assert!(!needs_validation(&request(None, Some(rule("app")))));
+
+        let mut updated = rule("Invalid");
+        updated["metadata"]["finalizers"] = json!(["vault2kube.sorah.jp/cleanup"]);
+        assert!(!needs_validation(&request(
+            Some(updated),
+            Some(rule("Invalid"))
+        )));
+
+        let mut deleting = rule("Invalid");
+        deleting["metadata"]["deletionTimestamp"] = json!("2020-01-01T00:00:00Z");
+        assert!(!needs_validation(&request(Some(deleting), None)));
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid: VaultStoreRule = serde_json::from_value(rule("app")).unwrap();
+        assert!(validate(&valid).is_empty());
+
+        let mut invalid: VaultStoreRule = serde_json::from_value(rule("Invalid")).unwrap();
+        invalid.spec.templates[0].template = "{{password".to_string();
+        invalid.spec.renew_before_seconds = None;
+        assert_eq!(validate(&invalid).len(), 3);
+    }
+}